@@ -86,6 +86,7 @@ install -d %{buildroot}%{pg_sharedir}
 install -m 755 %{buildpath}%{pg_libdir}%{name}.so %{buildroot}%{pg_libdir}
 install -m 755 %{buildpath}%{pg_sharedir}%{name}.control %{buildroot}%{pg_sharedir}
 install -m 755 %{buildpath}%{pg_sharedir}%{name}--%{version}.sql %{buildroot}%{pg_sharedir}
+install -m 755 %{buildpath}%{pg_sharedir}%{name}--*--%{version}.sql %{buildroot}%{pg_sharedir}
 
 %files
 %defattr(-,root,root,-)
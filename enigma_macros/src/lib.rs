@@ -58,6 +58,9 @@ pub fn enigma_derive(input: TokenStream) -> TokenStream {
             }
         }
     }
+    // type modifier without key names
+    let key_names = ! tokens.clone().into_iter()
+        .any(|token| token.to_string() == "NumericTypmod");
     for token in tokens {
         match token.to_string().as_str() {
             "BinaryFuncs" => {
@@ -70,7 +73,7 @@ pub fn enigma_derive(input: TokenStream) -> TokenStream {
                 from_into_datum = derive_from_into_datum(&input);
             },
             "InOutFuncs" => {
-                in_out_funcs = derive_in_out_funcs(&input);
+                in_out_funcs = derive_in_out_funcs(&input, key_names);
             },
             "TryFromString" => {
                 try_from_string = derive_try_from_string(&input);
@@ -79,9 +82,12 @@ pub fn enigma_derive(input: TokenStream) -> TokenStream {
                 binary_funcs = derive_binary_funcs(&input);
                 cast_funcs = derive_cast_funcs(&input);
                 from_into_datum = derive_from_into_datum(&input);
-                in_out_funcs = derive_in_out_funcs(&input);
+                in_out_funcs = derive_in_out_funcs(&input, key_names);
                 try_from_string = derive_try_from_string(&input);
             },
+            "NumericTypmod" => {
+                // see key_names above
+            },
             "," => {
                 // separator is also a token
             },
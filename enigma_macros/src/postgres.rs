@@ -6,7 +6,10 @@ use syn::{DeriveInput, Ident};
  * POSTGRES CREATE TYPE FUNCTIONS *
  * ********************************/
 
-pub fn derive_in_out_funcs(ast: &DeriveInput) -> TokenStream {
+/// `key_names`: the type modifier can be a public key name, resolved to
+/// the key id in `_enigma_public_keys`
+pub fn derive_in_out_funcs(ast: &DeriveInput, key_names: bool) 
+-> TokenStream {
     // Get the name of the struct
     let name = &ast.ident;
     let funcname_in = 
@@ -15,6 +18,8 @@ pub fn derive_in_out_funcs(ast: &DeriveInput) -> TokenStream {
         Ident::new(&format!("{name}_output").to_lowercase(), name.span());
     let funcname_typmod = Ident::new(
             &format!("{name}_typmod_in").to_lowercase(), name.span());
+    let funcname_typmod_out = Ident::new(
+            &format!("{name}_typmod_out").to_lowercase(), name.span());
     // Error messages
     let e_ambiguous = format!("INPUT: {name} Typmod is ambiguous.\n\
                     You should cast the value as ::Text\n\
@@ -25,6 +30,18 @@ pub fn derive_in_out_funcs(ast: &DeriveInput) -> TokenStream {
         format!("{name} type modifier must be a single integer value");
     let e_possitive_int = 
         format!("{name} type modifier must be a positive integer");
+    let (typmod_in_options, typmod_in_doc, typmod_parse) = if key_names {
+        (quote! { stable }, "Key names are resolved to the key id.",
+        quote! {
+            match modifier.parse::<i32>() {
+                Ok(i) => i,
+                Err(_) => crate::pub_key::key_id_from_name(modifier)?
+            }
+        })
+    } else {
+        (quote! { immutable, parallel_safe }, "Only key ids, no key names.",
+        quote! { modifier.parse::<i32>().map_err(|_| #e_possitive_int)? })
+    };
 
     quote! {
         /// INPUT function for CREATE TYPE
@@ -61,26 +78,39 @@ pub fn derive_in_out_funcs(ast: &DeriveInput) -> TokenStream {
         }
 
         /// TYPMOD_IN function for CREATE TYPE.
-        /// converts typmod from cstring to i32
-        #[pg_extern(immutable, parallel_safe, requires = [ "shell_type" ])]
+        /// converts typmod from cstring to i32.
+        #[doc = #typmod_in_doc]
+        #[pg_extern(#typmod_in_options, requires = [ "shell_type" ])]
         fn #funcname_typmod(input: Array<&CStr>) 
         -> Result<i32, Box<dyn std::error::Error + 'static>> {
             debug2!("TYPMOD_IN");
             if input.len() != 1 {
                 return Err(#e_single_int.into());
             }
-            let typmod = input.iter() // iterator
+            let modifier = input.iter() // iterator
             .next() // Option<Item>
             .ok_or("No Item")? // Item
             .ok_or("Null item")? // &Cstr
-            .to_str()? //&str
-            .parse::<i32>()?; // i32
+            .to_str()?; //&str
+            let typmod = #typmod_parse;
             debug1!("TYPMOD_IN({typmod})");
             if typmod < 0 {
                 return Err(#e_possitive_int.into());
             }
             Ok(typmod)
         }
+
+        /// TYPMOD_OUT function for CREATE TYPE.
+        /// converts typmod to "(key_id)" cstring, as in `Enigma(2)`.
+        /// Key names are not shown: pg_dump runs with an empty
+        /// `search_path` and must be able to restore the column type.
+        #[pg_extern(immutable, parallel_safe, requires = [ "shell_type" ])]
+        fn #funcname_typmod_out(typmod: i32) -> &'static CStr {
+            debug2!("TYPMOD_OUT({typmod})");
+            let mut buffer = StringInfo::new();
+            buffer.push_str(format!("({typmod})").as_str());
+            unsafe { buffer.leak_cstr() }
+        }
     }
 }
 
@@ -155,6 +185,8 @@ pub fn derive_cast_funcs(ast: &DeriveInput) -> TokenStream {
             &format!("string_as_{name}").to_lowercase(), name.span());
     let funcname_sizing = Ident::new(
             &format!("{name}_as_{name}").to_lowercase(), name.span());
+    let funcname_reencrypt = Ident::new(
+            &format!("{name}_reencrypt").to_lowercase(), name.span());
     let d2_assignment = format!( "CAST(Text as {}): \
             ARGUMENTS: explicit: {{explicit}},  Typmod: {{typmod}}",
             name);
@@ -186,7 +218,7 @@ pub fn derive_cast_funcs(ast: &DeriveInput) -> TokenStream {
             debug2!(#d2_sizing);
             debug5!("Original: {:?}", original);
             if original.is_encrypted() {
-                // TODO: if original.key_id != key_id {try_reencrypt()} 
+                // keeps its key id, see enigma_reencrypt()
                 return Ok(original);
             } 
             let key_id = match typmod {
@@ -203,6 +235,32 @@ pub fn derive_cast_funcs(ast: &DeriveInput) -> TokenStream {
             debug2!("Encrypting plain message with key ID: {key_id}");
             original.encrypt(key_id)
         }
+
+        /// Decrypts the value with the session private key and encrypts 
+        /// it again with the `new_key_id` public key.
+        #[pg_extern(volatile, name = "enigma_reencrypt")]
+        fn #funcname_reencrypt(value: #name, new_key_id: i32) 
+        -> Result<#name, Box<dyn std::error::Error + 'static>> {
+            value.reencrypt(new_key_id)
+        }
+
+        impl #name {
+            /// Decrypts the value and encrypts it with key `id`. 
+            /// Returns an error if the private key is not set.
+            pub fn reencrypt(self, id: i32) 
+            -> Result<Self, Box<dyn std::error::Error + 'static>> {
+                if id >= 0 && self.key_id() == Some(id as u32) {
+                    return Ok(self);
+                }
+                let decrypted = self.decrypt()?;
+                if let Some(key_id) = decrypted.key_id() {
+                    return Err(format!(
+                        "No private key with key_id {} to re-encrypt", 
+                        key_id).into());
+                }
+                decrypted.encrypt(id)
+            }
+        }
     }
 }
 
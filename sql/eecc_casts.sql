@@ -0,0 +1,5 @@
+-- assignment casts
+CREATE CAST (varchar AS Eecc) WITH FUNCTION string_as_eecc AS ASSIGNMENT;
+CREATE CAST (text AS Eecc) WITH FUNCTION string_as_eecc AS ASSIGNMENT;
+-- typmod workaround cast
+CREATE CAST (Eecc AS Eecc) WITH FUNCTION eecc_as_eecc AS IMPLICIT;
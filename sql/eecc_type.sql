@@ -0,0 +1,9 @@
+CREATE TYPE Eecc (
+	INPUT  = eecc_input,
+	OUTPUT = eecc_output,
+	RECEIVE = eecc_receive,
+	SEND = eecc_send,
+	TYPMOD_IN = eecc_typmod_in,
+	TYPMOD_OUT = eecc_typmod_out
+);
+
@@ -0,0 +1,32 @@
+-- Re-encryption jobs run by the jobs background worker
+CREATE TABLE IF NOT EXISTS _enigma_jobs (
+	id SERIAL PRIMARY KEY,
+	relid REGCLASS NOT NULL,
+	column_name NAME NOT NULL,
+	old_key_id INT NOT NULL,
+	new_key_id INT NOT NULL,
+	key_file TEXT NOT NULL,
+	pass_file TEXT,
+	batch_size INT NOT NULL DEFAULT 1000,
+	throttle_ms INT NOT NULL DEFAULT 100,
+	status TEXT NOT NULL DEFAULT 'queued' CHECK (status IN 
+		('queued', 'running', 'done', 'failed', 'cancelled')),
+	last_ctid TID,
+	reencrypted BIGINT NOT NULL DEFAULT 0,
+	remaining BIGINT,
+	error TEXT,
+	queued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	started_at TIMESTAMPTZ,
+	updated_at TIMESTAMPTZ,
+	finished_at TIMESTAMPTZ
+);
+
+-- Jobs status, without key and passphrase file paths
+CREATE VIEW enigma_jobs AS
+SELECT id, relid AS table_name, column_name, old_key_id, new_key_id,
+	status, reencrypted, remaining,
+	round(100.0 * reencrypted / NULLIF(reencrypted + remaining, 0), 1) 
+		AS progress,
+	batch_size, throttle_ms, last_ctid AS checkpoint, error,
+	queued_at, started_at, updated_at, finished_at
+FROM _enigma_jobs;
@@ -3,6 +3,7 @@ CREATE TYPE Enigma (
 	OUTPUT = enigma_output,
 	RECEIVE = enigma_receive,
 	SEND = enigma_send,
-	TYPMOD_IN = enigma_typmod_in
+	TYPMOD_IN = enigma_typmod_in,
+	TYPMOD_OUT = enigma_typmod_out
 );
 
@@ -3,6 +3,7 @@ CREATE TYPE Epgp (
 	OUTPUT = epgp_output,
 	RECEIVE = epgp_receive,
 	SEND = epgp_send,
-	TYPMOD_IN = epgp_typmod_in
+	TYPMOD_IN = epgp_typmod_in,
+	TYPMOD_OUT = epgp_typmod_out
 );
 
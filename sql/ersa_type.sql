@@ -3,6 +3,7 @@ CREATE TYPE Ersa (
 	OUTPUT = ersa_output,
 	RECEIVE = ersa_receive,
 	SEND = ersa_send,
-	TYPMOD_IN = ersa_typmod_in
+	TYPMOD_IN = ersa_typmod_in,
+	TYPMOD_OUT = ersa_typmod_out
 );
 
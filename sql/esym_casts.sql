@@ -0,0 +1,5 @@
+-- assignment casts
+CREATE CAST (varchar AS Esym) WITH FUNCTION string_as_esym AS ASSIGNMENT;
+CREATE CAST (text AS Esym) WITH FUNCTION string_as_esym AS ASSIGNMENT;
+-- typmod workaround cast
+CREATE CAST (Esym AS Esym) WITH FUNCTION esym_as_esym AS IMPLICIT;
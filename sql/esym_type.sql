@@ -0,0 +1,9 @@
+CREATE TYPE Esym (
+	INPUT  = esym_input,
+	OUTPUT = esym_output,
+	RECEIVE = esym_receive,
+	SEND = esym_send,
+	TYPMOD_IN = esym_typmod_in,
+	TYPMOD_OUT = esym_typmod_out
+);
+
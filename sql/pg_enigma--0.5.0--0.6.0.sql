@@ -0,0 +1,543 @@
+-- Upgrade pg_enigma from 0.5.0 to 0.6.0
+-- ALTER EXTENSION pg_enigma UPDATE TO '0.6.0';
+\echo Use "ALTER EXTENSION pg_enigma UPDATE" to load this file. \quit
+
+/*********************
+ * PUBLIC KEYS TABLE *
+ *********************/
+
+-- Key name and metadata
+ALTER TABLE _enigma_public_keys
+	ADD COLUMN IF NOT EXISTS name TEXT UNIQUE CHECK (name !~ '^[0-9]+$'),
+	ADD COLUMN IF NOT EXISTS fingerprint TEXT,
+	ADD COLUMN IF NOT EXISTS algorithm TEXT,
+	ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ,
+	ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ,
+	ADD COLUMN IF NOT EXISTS inserted_by NAME DEFAULT current_user,
+	ADD COLUMN IF NOT EXISTS inserted_at TIMESTAMPTZ DEFAULT now();
+-- Named key columns need the keys table to restore the dump
+SELECT pg_catalog.pg_extension_config_dump('_enigma_public_keys', '');
+-- Public keys metadata, without the armored key
+CREATE VIEW enigma_keys AS
+SELECT id, name, fingerprint, algorithm, created_at, expires_at,
+	expires_at < now() AS expired, inserted_by, inserted_at
+FROM _enigma_public_keys;
+
+/***************
+ * KEY HISTORY *
+ ***************/
+
+-- Fingerprint and metadata of the keys set before 0.6.0, so that
+-- set_public_key() can not swap them
+CREATE FUNCTION _enigma_backfill_public_key(id INT) RETURNS TEXT
+STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', '_enigma_backfill_public_key_wrapper';
+SELECT _enigma_backfill_public_key(id) FROM _enigma_public_keys
+WHERE fingerprint IS NULL;
+-- Every public key version, kept after the key is replaced or deleted
+CREATE TABLE IF NOT EXISTS _enigma_public_key_history (
+	id INT NOT NULL,
+	version INT NOT NULL,
+	public_key TEXT,
+	fingerprint TEXT,
+	algorithm TEXT,
+	created_at TIMESTAMPTZ,
+	expires_at TIMESTAMPTZ,
+	inserted_by NAME,
+	inserted_at TIMESTAMPTZ,
+	PRIMARY KEY (id, version)
+);
+SELECT pg_catalog.pg_extension_config_dump('_enigma_public_key_history', '');
+-- Keys set before 0.6.0 are their first version
+INSERT INTO _enigma_public_key_history(id, version, public_key, 
+	fingerprint, algorithm, created_at, expires_at, inserted_by, inserted_at)
+SELECT id, 1, public_key, fingerprint, algorithm, created_at, expires_at,
+	inserted_by, inserted_at
+FROM _enigma_public_keys
+ON CONFLICT DO NOTHING;
+-- New key version for every new key fingerprint.
+-- Restoring a dump copies the history before the keys, so a key that is
+-- already the latest version of its id gets no new version.
+CREATE FUNCTION _enigma_public_key_version() RETURNS trigger
+LANGUAGE plpgsql AS $$
+BEGIN
+	IF EXISTS (SELECT FROM @extschema@._enigma_public_key_history
+		WHERE id = NEW.id AND fingerprint IS NOT DISTINCT FROM NEW.fingerprint
+		AND version = (SELECT max(version)
+			FROM @extschema@._enigma_public_key_history WHERE id = NEW.id))
+	THEN
+		RETURN NULL;
+	END IF;
+	INSERT INTO @extschema@._enigma_public_key_history(id, version,
+		public_key, fingerprint, algorithm, created_at, expires_at,
+		inserted_by, inserted_at)
+	SELECT NEW.id, COALESCE(max(version), 0) + 1, NEW.public_key,
+		NEW.fingerprint, NEW.algorithm, NEW.created_at, NEW.expires_at,
+		NEW.inserted_by, NEW.inserted_at
+	FROM @extschema@._enigma_public_key_history WHERE id = NEW.id
+	ON CONFLICT DO NOTHING;
+	RETURN NULL;
+END;
+$$;
+CREATE TRIGGER _enigma_public_key_inserted
+AFTER INSERT ON _enigma_public_keys
+FOR EACH ROW EXECUTE FUNCTION _enigma_public_key_version();
+CREATE TRIGGER _enigma_public_key_replaced
+AFTER UPDATE ON _enigma_public_keys
+FOR EACH ROW WHEN (OLD.fingerprint IS DISTINCT FROM NEW.fingerprint)
+EXECUTE FUNCTION _enigma_public_key_version();
+-- Public key versions, matched to values by enigma_key_fingerprint()
+CREATE VIEW enigma_key_history AS
+SELECT id, version, fingerprint, left(fingerprint, 16) AS key_print,
+	algorithm, created_at, expires_at, inserted_by, inserted_at
+FROM _enigma_public_key_history;
+
+-- Every backend reloads its cached public keys after the keys table changes
+CREATE FUNCTION _enigma_public_keys_changed() RETURNS trigger
+LANGUAGE c AS 'MODULE_PATHNAME', '_enigma_public_keys_changed_wrapper';
+CREATE TRIGGER _enigma_public_keys_changed
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON _enigma_public_keys
+FOR EACH STATEMENT EXECUTE FUNCTION _enigma_public_keys_changed();
+
+/*******************************
+ * ENIGMA, EPGP AND ERSA TYPES *
+ *******************************/
+
+-- TYPMOD_OUT functions, so pg_dump shows enigma(2)
+CREATE FUNCTION enigma_typmod_out(typmod INT) RETURNS cstring
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'enigma_typmod_out_wrapper';
+CREATE FUNCTION epgp_typmod_out(typmod INT) RETURNS cstring
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'epgp_typmod_out_wrapper';
+CREATE FUNCTION ersa_typmod_out(typmod INT) RETURNS cstring
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'ersa_typmod_out_wrapper';
+ALTER TYPE Enigma SET (TYPMOD_OUT = enigma_typmod_out);
+ALTER TYPE Epgp SET (TYPMOD_OUT = epgp_typmod_out);
+ALTER TYPE Ersa SET (TYPMOD_OUT = ersa_typmod_out);
+-- Type modifiers resolve key names from the keys table
+ALTER FUNCTION enigma_typmod_in(cstring[]) STABLE PARALLEL UNSAFE;
+ALTER FUNCTION epgp_typmod_in(cstring[]) STABLE PARALLEL UNSAFE;
+ALTER FUNCTION ersa_typmod_in(cstring[]) STABLE PARALLEL UNSAFE;
+
+-- Key rotation
+CREATE FUNCTION enigma_reencrypt(value Enigma, new_key_id INT)
+RETURNS Enigma STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'enigma_reencrypt_wrapper';
+CREATE FUNCTION enigma_reencrypt(value Epgp, new_key_id INT)
+RETURNS Epgp STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'epgp_reencrypt_wrapper';
+CREATE FUNCTION enigma_reencrypt(value Ersa, new_key_id INT)
+RETURNS Ersa STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'ersa_reencrypt_wrapper';
+CREATE FUNCTION enigma_encrypt(value TEXT, key_ids INT[])
+RETURNS Enigma STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'enigma_encrypt_wrapper';
+CREATE FUNCTION epgp_encrypt(value TEXT, key_ids INT[])
+RETURNS Epgp STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'epgp_encrypt_wrapper';
+
+/*******************
+ * EECC, ESYM TYPES *
+ *******************/
+
+-- Eecc shell_type
+CREATE TYPE Eecc;
+CREATE FUNCTION eecc_input(input cstring, oid oid, typmod INT)
+RETURNS Eecc STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'eecc_input_wrapper';
+CREATE FUNCTION eecc_output(value Eecc)
+RETURNS cstring STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'eecc_output_wrapper';
+CREATE FUNCTION eecc_typmod_in(input cstring[])
+RETURNS INT STABLE STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'eecc_typmod_in_wrapper';
+CREATE FUNCTION eecc_typmod_out(typmod INT) RETURNS cstring
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'eecc_typmod_out_wrapper';
+CREATE FUNCTION eecc_receive(internal internal, oid oid, typmod INT)
+RETURNS Eecc STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'eecc_receive_wrapper';
+CREATE FUNCTION eecc_send(value Eecc)
+RETURNS bytea STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'eecc_send_wrapper';
+CREATE TYPE Eecc (
+	INPUT  = eecc_input,
+	OUTPUT = eecc_output,
+	RECEIVE = eecc_receive,
+	SEND = eecc_send,
+	TYPMOD_IN = eecc_typmod_in,
+	TYPMOD_OUT = eecc_typmod_out
+);
+CREATE FUNCTION string_as_eecc(original TEXT, typmod INT, explicit bool)
+RETURNS Eecc STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'string_as_eecc_wrapper';
+CREATE FUNCTION eecc_as_eecc(original Eecc, typmod INT, explicit bool)
+RETURNS Eecc STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'eecc_as_eecc_wrapper';
+CREATE FUNCTION enigma_reencrypt(value Eecc, new_key_id INT)
+RETURNS Eecc STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'eecc_reencrypt_wrapper';
+-- assignment casts
+CREATE CAST (varchar AS Eecc) WITH FUNCTION string_as_eecc AS ASSIGNMENT;
+CREATE CAST (text AS Eecc) WITH FUNCTION string_as_eecc AS ASSIGNMENT;
+-- typmod workaround cast
+CREATE CAST (Eecc AS Eecc) WITH FUNCTION eecc_as_eecc AS IMPLICIT;
+
+-- Esym shell_type
+CREATE TYPE Esym;
+CREATE FUNCTION esym_input(input cstring, oid oid, typmod INT)
+RETURNS Esym STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'esym_input_wrapper';
+CREATE FUNCTION esym_output(value Esym)
+RETURNS cstring STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'esym_output_wrapper';
+CREATE FUNCTION esym_typmod_in(input cstring[])
+RETURNS INT IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'esym_typmod_in_wrapper';
+CREATE FUNCTION esym_typmod_out(typmod INT) RETURNS cstring
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'esym_typmod_out_wrapper';
+CREATE FUNCTION esym_receive(internal internal, oid oid, typmod INT)
+RETURNS Esym STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'esym_receive_wrapper';
+CREATE FUNCTION esym_send(value Esym)
+RETURNS bytea STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'esym_send_wrapper';
+CREATE TYPE Esym (
+	INPUT  = esym_input,
+	OUTPUT = esym_output,
+	RECEIVE = esym_receive,
+	SEND = esym_send,
+	TYPMOD_IN = esym_typmod_in,
+	TYPMOD_OUT = esym_typmod_out
+);
+CREATE FUNCTION string_as_esym(original TEXT, typmod INT, explicit bool)
+RETURNS Esym STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'string_as_esym_wrapper';
+CREATE FUNCTION esym_as_esym(original Esym, typmod INT, explicit bool)
+RETURNS Esym STABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'esym_as_esym_wrapper';
+CREATE FUNCTION enigma_reencrypt(value Esym, new_key_id INT)
+RETURNS Esym STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'esym_reencrypt_wrapper';
+-- assignment casts
+CREATE CAST (varchar AS Esym) WITH FUNCTION string_as_esym AS ASSIGNMENT;
+CREATE CAST (text AS Esym) WITH FUNCTION string_as_esym AS ASSIGNMENT;
+-- typmod workaround cast
+CREATE CAST (Esym AS Esym) WITH FUNCTION esym_as_esym AS IMPLICIT;
+
+/*****************
+ * KEY FUNCTIONS *
+ *****************/
+
+-- Private keys time to live
+DROP FUNCTION set_private_key(INT, TEXT, TEXT);
+CREATE FUNCTION set_private_key(id INT, key TEXT, pass TEXT,
+	ttl interval DEFAULT NULL)
+RETURNS TEXT STABLE
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_private_key_wrapper';
+CREATE FUNCTION set_private_key(name TEXT, key TEXT, pass TEXT,
+	ttl interval DEFAULT NULL)
+RETURNS TEXT STABLE
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_private_key_by_name_wrapper';
+CREATE FUNCTION set_private_key(id INT, key TEXT)
+RETURNS TEXT STABLE STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_private_key_without_pass_wrapper';
+CREATE FUNCTION set_private_key(name TEXT, key TEXT)
+RETURNS TEXT STABLE STRICT
+LANGUAGE c AS 'MODULE_PATHNAME',
+	'set_private_key_without_pass_by_name_wrapper';
+CREATE FUNCTION set_private_key_local(id INT, key TEXT, pass TEXT)
+RETURNS TEXT STABLE STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_private_key_local_wrapper';
+CREATE FUNCTION set_private_key_local(name TEXT, key TEXT, pass TEXT)
+RETURNS TEXT STABLE STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_private_key_local_by_name_wrapper';
+CREATE FUNCTION set_private_key_pkcs11(id INT, slot BIGINT, label TEXT,
+	pin TEXT, ttl interval DEFAULT NULL)
+RETURNS TEXT STABLE
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_private_key_pkcs11_wrapper';
+DROP FUNCTION set_private_key_from_file(INT, TEXT, TEXT);
+CREATE FUNCTION set_private_key_from_file(id INT, file_path TEXT, pass TEXT,
+	ttl interval DEFAULT NULL)
+RETURNS TEXT STABLE
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_private_key_from_file_wrapper';
+CREATE FUNCTION set_private_key_from_file(name TEXT, file_path TEXT,
+	pass TEXT, ttl interval DEFAULT NULL)
+RETURNS TEXT STABLE
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_private_key_from_file_by_name_wrapper';
+CREATE FUNCTION forget_private_key(name TEXT)
+RETURNS TEXT STABLE STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'forget_private_key_by_name_wrapper';
+
+-- The public keys tables are only written by SECURITY DEFINER functions
+ALTER FUNCTION set_public_key(INT, TEXT) SECURITY DEFINER
+	SET search_path TO pg_catalog, @extschema@, pg_temp;
+CREATE FUNCTION set_public_key(id INT, key TEXT, name TEXT)
+RETURNS TEXT STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, @extschema@, pg_temp
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_named_public_key_wrapper';
+CREATE FUNCTION set_public_key(name TEXT, key TEXT)
+RETURNS TEXT STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, @extschema@, pg_temp
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_public_key_by_name_wrapper';
+CREATE FUNCTION replace_public_key(id INT, key TEXT)
+RETURNS TEXT STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, @extschema@, pg_temp
+LANGUAGE c AS 'MODULE_PATHNAME', 'replace_public_key_wrapper';
+CREATE FUNCTION replace_public_key(name TEXT, key TEXT)
+RETURNS TEXT STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, @extschema@, pg_temp
+LANGUAGE c AS 'MODULE_PATHNAME', 'replace_public_key_by_name_wrapper';
+CREATE FUNCTION delete_public_key(id INT, force bool DEFAULT false)
+RETURNS TEXT STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, @extschema@, pg_temp
+LANGUAGE c AS 'MODULE_PATHNAME', 'delete_public_key_wrapper';
+CREATE FUNCTION delete_public_key(name TEXT, force bool DEFAULT false)
+RETURNS TEXT STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, @extschema@, pg_temp
+LANGUAGE c AS 'MODULE_PATHNAME', 'delete_public_key_by_name_wrapper';
+CREATE FUNCTION forget_public_key(name TEXT)
+RETURNS TEXT STABLE STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'forget_public_key_by_name_wrapper';
+ALTER FUNCTION set_public_key_from_file(INT, TEXT) VOLATILE;
+CREATE FUNCTION set_public_key_from_file(id INT, file_path TEXT, name TEXT)
+RETURNS TEXT STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_named_public_key_from_file_wrapper';
+CREATE FUNCTION set_public_key_from_file(name TEXT, file_path TEXT)
+RETURNS TEXT STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_public_key_from_file_by_name_wrapper';
+
+/****************
+ * KEY ROTATION *
+ ****************/
+
+CREATE FUNCTION enigma_key_id(value anyelement)
+RETURNS INT IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'enigma_key_id_wrapper';
+CREATE FUNCTION enigma_key_fingerprint(value anyelement)
+RETURNS TEXT IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c AS 'MODULE_PATHNAME', 'enigma_key_fingerprint_wrapper';
+CREATE FUNCTION enigma_rotate_column(rel regclass, "column" TEXT,
+	new_key_id INT, batch_size INT DEFAULT 1000)
+RETURNS TABLE (batch INT, reencrypted BIGINT, remaining BIGINT) STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'enigma_rotate_column_wrapper';
+
+-- Re-encryption jobs run by the jobs background worker
+CREATE TABLE IF NOT EXISTS _enigma_jobs (
+	id SERIAL PRIMARY KEY,
+	relid REGCLASS NOT NULL,
+	column_name NAME NOT NULL,
+	old_key_id INT NOT NULL,
+	new_key_id INT NOT NULL,
+	key_file TEXT NOT NULL,
+	pass_file TEXT,
+	batch_size INT NOT NULL DEFAULT 1000,
+	throttle_ms INT NOT NULL DEFAULT 100,
+	status TEXT NOT NULL DEFAULT 'queued' CHECK (status IN
+		('queued', 'running', 'done', 'failed', 'cancelled')),
+	last_ctid TID,
+	reencrypted BIGINT NOT NULL DEFAULT 0,
+	remaining BIGINT,
+	error TEXT,
+	queued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	started_at TIMESTAMPTZ,
+	updated_at TIMESTAMPTZ,
+	finished_at TIMESTAMPTZ
+);
+
+-- Jobs status, without key and passphrase file paths
+CREATE VIEW enigma_jobs AS
+SELECT id, relid AS table_name, column_name, old_key_id, new_key_id,
+	status, reencrypted, remaining,
+	round(100.0 * reencrypted / NULLIF(reencrypted + remaining, 0), 1)
+		AS progress,
+	batch_size, throttle_ms, last_ctid AS checkpoint, error,
+	queued_at, started_at, updated_at, finished_at
+FROM _enigma_jobs;
+CREATE FUNCTION enigma_queue_rotation(rel regclass, "column" TEXT,
+	old_key_id INT, new_key_id INT, key_file TEXT,
+	pass_file TEXT DEFAULT NULL, batch_size INT DEFAULT 1000,
+	throttle_ms INT DEFAULT 100)
+RETURNS INT
+LANGUAGE c AS 'MODULE_PATHNAME', 'enigma_queue_rotation_wrapper';
+CREATE FUNCTION enigma_cancel_job(id INT)
+RETURNS TEXT STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'enigma_cancel_job_wrapper';
+
+/*********
+ * VAULT *
+ *********/
+
+-- Roles allowed to use each private key in the shared memory vault
+-- Members of the role (with its privileges) can use the key too
+CREATE TABLE IF NOT EXISTS _enigma_vault_grants (
+	id INT NOT NULL,
+	role NAME NOT NULL,
+	granted_by NAME DEFAULT current_user,
+	granted_at TIMESTAMPTZ DEFAULT now(),
+	PRIMARY KEY (id, role)
+);
+SELECT pg_catalog.pg_extension_config_dump('_enigma_vault_grants', '');
+-- Whether role `role_oid` has the privileges of a role granted vault private
+-- key `key_id`. Runs as the extension owner, with a fixed search_path, so
+-- roles neither read the grants nor shadow them with a temporary table.
+CREATE FUNCTION _enigma_vault_granted(key_id INT, role_oid OID)
+RETURNS BOOLEAN
+LANGUAGE sql STABLE SECURITY DEFINER
+SET search_path = pg_catalog, pg_temp AS $$
+	SELECT EXISTS (SELECT FROM @extschema@._enigma_vault_grants g
+		JOIN pg_catalog.pg_roles r ON r.rolname = g.role
+		WHERE g.id = key_id AND pg_catalog.pg_has_role(role_oid, r.oid, 'USAGE'));
+$$;
+CREATE FUNCTION set_vault_private_key(id INT, key TEXT, pass TEXT)
+RETURNS TEXT STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_vault_private_key_wrapper';
+CREATE FUNCTION set_vault_private_key(name TEXT, key TEXT, pass TEXT)
+RETURNS TEXT STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_vault_private_key_by_name_wrapper';
+CREATE FUNCTION forget_vault_private_key(id INT)
+RETURNS TEXT STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'forget_vault_private_key_wrapper';
+CREATE FUNCTION forget_vault_private_key(name TEXT)
+RETURNS TEXT STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'forget_vault_private_key_by_name_wrapper';
+CREATE FUNCTION grant_vault_private_key(id INT, role TEXT)
+RETURNS TEXT STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, @extschema@, pg_temp
+LANGUAGE c AS 'MODULE_PATHNAME', 'grant_vault_private_key_wrapper';
+CREATE FUNCTION revoke_vault_private_key(id INT, role TEXT)
+RETURNS TEXT STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, @extschema@, pg_temp
+LANGUAGE c AS 'MODULE_PATHNAME', 'revoke_vault_private_key_wrapper';
+
+/***************
+ * SECRET KEYS *
+ ***************/
+
+-- Key derivation function and salt of each secret key passphrase,
+-- like argon2id$m=19456,t=2,p=1$<base64 salt>. The salt is not secret,
+-- but Esym values can not be decrypted without it.
+CREATE TABLE IF NOT EXISTS _enigma_secret_keys (
+	id INT PRIMARY KEY,
+	kdf TEXT NOT NULL,
+	created_by NAME DEFAULT current_user,
+	created_at TIMESTAMPTZ DEFAULT now()
+);
+SELECT pg_catalog.pg_extension_config_dump('_enigma_secret_keys', '');
+CREATE FUNCTION _enigma_new_secret_key_kdf(id INT)
+RETURNS TEXT STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, @extschema@, pg_temp
+LANGUAGE c AS 'MODULE_PATHNAME', '_enigma_new_secret_key_kdf_wrapper';
+CREATE FUNCTION set_secret_key(id INT, key TEXT)
+RETURNS TEXT STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'set_secret_key_wrapper';
+CREATE FUNCTION forget_secret_key(id INT)
+RETURNS TEXT STABLE STRICT
+LANGUAGE c AS 'MODULE_PATHNAME', 'forget_secret_key_wrapper';
+
+/**************
+ * PRIVILEGES *
+ **************/
+
+-- Roles are shared by all databases: create them only once
+DO $$
+BEGIN
+	IF NOT EXISTS (SELECT FROM pg_roles WHERE rolname = 'enigma_reader') THEN
+		CREATE ROLE enigma_reader NOLOGIN;
+	END IF;
+	IF NOT EXISTS (SELECT FROM pg_roles WHERE rolname = 'enigma_admin') THEN
+		CREATE ROLE enigma_admin NOLOGIN IN ROLE enigma_reader;
+	END IF;
+END
+$$;
+
+-- Public keys are not secret: anyone can encrypt
+GRANT SELECT ON _enigma_public_keys, enigma_keys, enigma_key_history
+	TO PUBLIC;
+
+-- enigma_reader sets private keys to decrypt
+REVOKE EXECUTE ON FUNCTION
+	set_private_key(integer, text, text, interval),
+	set_private_key(text, text, text, interval),
+	set_private_key(integer, text),
+	set_private_key(text, text),
+	set_private_key_local(integer, text, text),
+	set_private_key_local(text, text, text),
+	set_private_key_from_file(integer, text, text, interval),
+	set_private_key_from_file(text, text, text, interval),
+	set_private_key_pkcs11(integer, bigint, text, text, interval)
+FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION
+	set_private_key(integer, text, text, interval),
+	set_private_key(text, text, text, interval),
+	set_private_key(integer, text),
+	set_private_key(text, text),
+	set_private_key_local(integer, text, text),
+	set_private_key_local(text, text, text),
+	set_private_key_from_file(integer, text, text, interval),
+	set_private_key_from_file(text, text, text, interval),
+	set_private_key_pkcs11(integer, bigint, text, text, interval)
+TO enigma_reader;
+
+-- enigma_admin manages the public keys
+REVOKE EXECUTE ON FUNCTION
+	set_public_key(integer, text),
+	set_public_key(integer, text, text),
+	set_public_key(text, text),
+	replace_public_key(integer, text),
+	replace_public_key(text, text),
+	delete_public_key(integer, boolean),
+	delete_public_key(text, boolean),
+	set_public_key_from_file(integer, text),
+	set_public_key_from_file(integer, text, text),
+	set_public_key_from_file(text, text)
+FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION
+	set_public_key(integer, text),
+	set_public_key(integer, text, text),
+	set_public_key(text, text),
+	replace_public_key(integer, text),
+	replace_public_key(text, text),
+	delete_public_key(integer, boolean),
+	delete_public_key(text, boolean),
+	set_public_key_from_file(integer, text),
+	set_public_key_from_file(integer, text, text),
+	set_public_key_from_file(text, text)
+TO enigma_admin;
+-- The keys tables are only written by the SECURITY DEFINER key functions
+-- and the key version trigger: enigma_admin has no DML on them
+
+-- Vault grants are only read by _enigma_vault_granted() (SECURITY
+-- DEFINER), enigma_admin manages the vault
+REVOKE EXECUTE ON FUNCTION
+	set_vault_private_key(integer, text, text),
+	set_vault_private_key(text, text, text),
+	forget_vault_private_key(integer),
+	forget_vault_private_key(text),
+	grant_vault_private_key(integer, text),
+	revoke_vault_private_key(integer, text)
+FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION
+	set_vault_private_key(integer, text, text),
+	set_vault_private_key(text, text, text),
+	forget_vault_private_key(integer),
+	forget_vault_private_key(text),
+	grant_vault_private_key(integer, text),
+	revoke_vault_private_key(integer, text)
+TO enigma_admin;
+
+-- Secret keys encrypt and decrypt Esym values: only enigma_reader sets
+-- them. The KDF salt of a new passphrase is only created by
+-- _enigma_new_secret_key_kdf() (SECURITY DEFINER)
+GRANT SELECT ON _enigma_secret_keys TO PUBLIC;
+REVOKE EXECUTE ON FUNCTION
+	set_secret_key(integer, text),
+	_enigma_new_secret_key_kdf(integer)
+FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION
+	set_secret_key(integer, text),
+	_enigma_new_secret_key_kdf(integer)
+TO enigma_reader;
@@ -0,0 +1,99 @@
+-- Roles are shared by all databases: create them only once
+DO $$
+BEGIN
+	IF NOT EXISTS (SELECT FROM pg_roles WHERE rolname = 'enigma_reader') THEN
+		CREATE ROLE enigma_reader NOLOGIN;
+	END IF;
+	IF NOT EXISTS (SELECT FROM pg_roles WHERE rolname = 'enigma_admin') THEN
+		CREATE ROLE enigma_admin NOLOGIN IN ROLE enigma_reader;
+	END IF;
+END
+$$;
+
+-- Public keys are not secret: anyone can encrypt
+GRANT SELECT ON _enigma_public_keys, enigma_keys, enigma_key_history
+	TO PUBLIC;
+
+-- enigma_reader sets private keys to decrypt
+REVOKE EXECUTE ON FUNCTION
+	set_private_key(integer, text, text, interval),
+	set_private_key(text, text, text, interval),
+	set_private_key(integer, text),
+	set_private_key(text, text),
+	set_private_key_local(integer, text, text),
+	set_private_key_local(text, text, text),
+	set_private_key_from_file(integer, text, text, interval),
+	set_private_key_from_file(text, text, text, interval),
+	set_private_key_pkcs11(integer, bigint, text, text, interval)
+FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION
+	set_private_key(integer, text, text, interval),
+	set_private_key(text, text, text, interval),
+	set_private_key(integer, text),
+	set_private_key(text, text),
+	set_private_key_local(integer, text, text),
+	set_private_key_local(text, text, text),
+	set_private_key_from_file(integer, text, text, interval),
+	set_private_key_from_file(text, text, text, interval),
+	set_private_key_pkcs11(integer, bigint, text, text, interval)
+TO enigma_reader;
+
+-- enigma_admin manages the public keys
+REVOKE EXECUTE ON FUNCTION
+	set_public_key(integer, text),
+	set_public_key(integer, text, text),
+	set_public_key(text, text),
+	replace_public_key(integer, text),
+	replace_public_key(text, text),
+	delete_public_key(integer, boolean),
+	delete_public_key(text, boolean),
+	set_public_key_from_file(integer, text),
+	set_public_key_from_file(integer, text, text),
+	set_public_key_from_file(text, text)
+FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION
+	set_public_key(integer, text),
+	set_public_key(integer, text, text),
+	set_public_key(text, text),
+	replace_public_key(integer, text),
+	replace_public_key(text, text),
+	delete_public_key(integer, boolean),
+	delete_public_key(text, boolean),
+	set_public_key_from_file(integer, text),
+	set_public_key_from_file(integer, text, text),
+	set_public_key_from_file(text, text)
+TO enigma_admin;
+-- The keys tables are only written by the SECURITY DEFINER key functions
+-- and the key version trigger: enigma_admin has no DML on them
+
+-- Vault grants are only read by _enigma_vault_granted() (SECURITY
+-- DEFINER), enigma_admin manages the vault
+REVOKE EXECUTE ON FUNCTION
+	set_vault_private_key(integer, text, text),
+	set_vault_private_key(text, text, text),
+	forget_vault_private_key(integer),
+	forget_vault_private_key(text),
+	grant_vault_private_key(integer, text),
+	revoke_vault_private_key(integer, text)
+FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION
+	set_vault_private_key(integer, text, text),
+	set_vault_private_key(text, text, text),
+	forget_vault_private_key(integer),
+	forget_vault_private_key(text),
+	grant_vault_private_key(integer, text),
+	revoke_vault_private_key(integer, text)
+TO enigma_admin;
+
+-- Secret keys encrypt and decrypt Esym values: only enigma_reader sets
+-- them. The KDF salt of a new passphrase is only created by
+-- _enigma_new_secret_key_kdf() (SECURITY DEFINER)
+GRANT SELECT ON _enigma_secret_keys TO PUBLIC;
+REVOKE EXECUTE ON FUNCTION
+	set_secret_key(integer, text),
+	_enigma_new_secret_key_kdf(integer)
+FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION
+	set_secret_key(integer, text),
+	_enigma_new_secret_key_kdf(integer)
+TO enigma_reader;
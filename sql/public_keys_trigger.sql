@@ -0,0 +1,4 @@
+-- Every backend reloads its cached public keys after the keys table changes
+CREATE TRIGGER _enigma_public_keys_changed
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON _enigma_public_keys
+FOR EACH STATEMENT EXECUTE FUNCTION _enigma_public_keys_changed();
@@ -0,0 +1,10 @@
+-- Key derivation function and salt of each secret key passphrase,
+-- like argon2id$m=19456,t=2,p=1$<base64 salt>. The salt is not secret,
+-- but Esym values can not be decrypted without it.
+CREATE TABLE IF NOT EXISTS _enigma_secret_keys (
+	id INT PRIMARY KEY,
+	kdf TEXT NOT NULL,
+	created_by NAME DEFAULT current_user,
+	created_at TIMESTAMPTZ DEFAULT now()
+);
+SELECT pg_catalog.pg_extension_config_dump('_enigma_secret_keys', '');
@@ -1,12 +1,81 @@
 -- Public keys table stores public keys permanently
 -- Public keys are stored as armored text
+-- Optional unique name can be used instead of the id in the type modifier
 CREATE TABLE IF NOT EXISTS _enigma_public_keys (
 	id INT PRIMARY KEY,
-	public_key TEXT 
+	public_key TEXT,
+	name TEXT UNIQUE CHECK (name !~ '^[0-9]+$'),
+	fingerprint TEXT,
+	algorithm TEXT,
+	created_at TIMESTAMPTZ,
+	expires_at TIMESTAMPTZ,
+	inserted_by NAME DEFAULT current_user,
+	inserted_at TIMESTAMPTZ DEFAULT now()
 );
+-- Named key columns need the keys table to restore the dump
+SELECT pg_catalog.pg_extension_config_dump('_enigma_public_keys', '');
+-- Every public key version, kept after the key is replaced or deleted
+CREATE TABLE IF NOT EXISTS _enigma_public_key_history (
+	id INT NOT NULL,
+	version INT NOT NULL,
+	public_key TEXT,
+	fingerprint TEXT,
+	algorithm TEXT,
+	created_at TIMESTAMPTZ,
+	expires_at TIMESTAMPTZ,
+	inserted_by NAME,
+	inserted_at TIMESTAMPTZ,
+	PRIMARY KEY (id, version)
+);
+SELECT pg_catalog.pg_extension_config_dump('_enigma_public_key_history', '');
+-- New key version for every new key fingerprint.
+-- Restoring a dump copies the history before the keys, so a key that is
+-- already the latest version of its id gets no new version.
+CREATE FUNCTION _enigma_public_key_version() RETURNS trigger
+LANGUAGE plpgsql AS $$
+BEGIN
+	IF EXISTS (SELECT FROM @extschema@._enigma_public_key_history
+		WHERE id = NEW.id AND fingerprint IS NOT DISTINCT FROM NEW.fingerprint
+		AND version = (SELECT max(version) 
+			FROM @extschema@._enigma_public_key_history WHERE id = NEW.id))
+	THEN
+		RETURN NULL;
+	END IF;
+	INSERT INTO @extschema@._enigma_public_key_history(id, version, 
+		public_key, fingerprint, algorithm, created_at, expires_at, 
+		inserted_by, inserted_at)
+	SELECT NEW.id, COALESCE(max(version), 0) + 1, NEW.public_key, 
+		NEW.fingerprint, NEW.algorithm, NEW.created_at, NEW.expires_at,
+		NEW.inserted_by, NEW.inserted_at
+	FROM @extschema@._enigma_public_key_history WHERE id = NEW.id
+	ON CONFLICT DO NOTHING;
+	RETURN NULL;
+END;
+$$;
+CREATE TRIGGER _enigma_public_key_inserted 
+AFTER INSERT ON _enigma_public_keys
+FOR EACH ROW EXECUTE FUNCTION _enigma_public_key_version();
+CREATE TRIGGER _enigma_public_key_replaced 
+AFTER UPDATE ON _enigma_public_keys
+FOR EACH ROW WHEN (OLD.fingerprint IS DISTINCT FROM NEW.fingerprint)
+EXECUTE FUNCTION _enigma_public_key_version();
+-- Public keys metadata, without the armored key
+CREATE VIEW enigma_keys AS
+SELECT id, name, fingerprint, algorithm, created_at, expires_at,
+	expires_at < now() AS expired, inserted_by, inserted_at
+FROM _enigma_public_keys;
+-- Public key versions, matched to values by enigma_key_fingerprint()
+CREATE VIEW enigma_key_history AS
+SELECT id, version, fingerprint, left(fingerprint, 16) AS key_print, 
+	algorithm, created_at, expires_at, inserted_by, inserted_at
+FROM _enigma_public_key_history;
 -- Enigma shell_type
 CREATE TYPE Enigma;
 -- Epgp shell_type
 CREATE TYPE Epgp;
 -- Ersa shell_type
 CREATE TYPE Ersa;
+-- Eecc shell_type
+CREATE TYPE Eecc;
+-- Esym shell_type
+CREATE TYPE Esym;
@@ -0,0 +1,21 @@
+-- Roles allowed to use each private key in the shared memory vault
+-- Members of the role (with its privileges) can use the key too
+CREATE TABLE IF NOT EXISTS _enigma_vault_grants (
+	id INT NOT NULL,
+	role NAME NOT NULL,
+	granted_by NAME DEFAULT current_user,
+	granted_at TIMESTAMPTZ DEFAULT now(),
+	PRIMARY KEY (id, role)
+);
+SELECT pg_catalog.pg_extension_config_dump('_enigma_vault_grants', '');
+-- Whether role `role_oid` has the privileges of a role granted vault private
+-- key `key_id`. Runs as the extension owner, with a fixed search_path, so
+-- roles neither read the grants nor shadow them with a temporary table.
+CREATE FUNCTION _enigma_vault_granted(key_id INT, role_oid OID)
+RETURNS BOOLEAN
+LANGUAGE sql STABLE SECURITY DEFINER
+SET search_path = pg_catalog, pg_temp AS $$
+	SELECT EXISTS (SELECT FROM @extschema@._enigma_vault_grants g
+		JOIN pg_catalog.pg_roles r ON r.rolname = g.role
+		WHERE g.id = key_id AND pg_catalog.pg_has_role(role_oid, r.oid, 'USAGE'));
+$$;
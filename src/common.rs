@@ -2,6 +2,11 @@
 pub const PLAIN_TAG: &str  = "PLAINMSG"; // 0x504C41494E4D5347
 pub const PLAIN_INT: u64   = 0x504C41494E4D5347; // "PLAINMSG"
 pub const SEPARATOR: char = '\n';
+pub const MOREKEYS_TAG: &str = "MOREKEYS"; // 0x4D4F52454B455953
+pub const MOREKEYS_INT: u64  = 0x4D4F52454B455953; // "MOREKEYS"
+pub const KEYPRINT_TAG: &str = "KEYPRINT"; // 0x4B45595052494E54
+pub const KEYPRINT_INT: u64  = 0x4B45595052494E54; // "KEYPRINT"
+const MOREKEYS_PAD: &str = "00000000";
 
 /// Enigma types must implement `Plain` trait for plain unencrypted payload
 pub trait Plain {
@@ -49,11 +54,20 @@ First 8 octets are Enigma tag `0x454E49474D417631`. It's value can be verified a
 
 Next 8 octets are hex-encoded 32-bit integer corresponding to enigma key_id in *typmod*. Since typmod is signed integer, maximum key_id value can not be greater than `2,147,483,647`.
 
-This fixed size header is for parsing efficiency.  **/
+This fixed size header is for parsing efficiency.  
+
+Optional More Keys header follows: `MOREKEYS` tag, 8 octets zero padding
+and a 16-octet hex-encoded key_id for each additional key.
+
+Optional Key Print header follows: `KEYPRINT` tag, 8 octets zero padding
+and the first 16 hex octets of the encrypting key fingerprint.  **/
 pub struct Header {
     pub tag: u64,
-    pub key: u32
-    // TODO: More Keys vector
+    pub key: u32,
+    /// Additional key_ids from More Keys header
+    pub more: Vec<u32>,
+    /// Encrypting key fingerprint (first 64 bits) from Key Print header
+    pub print: Option<u64>
 }
 
 /// Try to read Enigma header from given string.
@@ -64,14 +78,103 @@ impl TryFrom<&str> for Header {
         if full_header.len() < 16 {
             return Err("Wrong header".into());
         }
-        let (hdr,_) = full_header.split_at(16);
+        let (hdr, extensions) = full_header.split_at(16);
         let (stag, skey) = hdr.split_at(8);
+        let (more, print) = extensions_from(extensions)?;
         let ret = Header {
             tag: u64::from_be_bytes(stag.as_bytes().try_into()?),
-            key: u32::from_str_radix(skey, 16)?
+            key: u32::from_str_radix(skey, 16)?,
+            more,
+            print
         };
         Ok(ret)
     }
 }
 
+/// More Keys header for the given additional key_ids.
+/// Empty string if there are no additional keys.
+pub fn more_keys_header(more: &[u32]) -> String {
+    if more.is_empty() {
+        return String::new();
+    }
+    let mut hdr = format!("{}{}", MOREKEYS_TAG, MOREKEYS_PAD);
+    for key in more {
+        hdr.push_str(&format!("{:016X}", *key as u64));
+    }
+    hdr
+}
+
+/// Key Print header for the given key fingerprint.
+/// Empty string if there is no fingerprint.
+pub fn key_print_header(print: &Option<u64>) -> String {
+    match print {
+        Some(p) => format!("{}{}{:016X}", KEYPRINT_TAG, MOREKEYS_PAD, p),
+        None => String::new()
+    }
+}
+
+/// First 64 bits of a hex-encoded key fingerprint
+pub fn key_print_from(fingerprint: &str) -> Option<u64> {
+    fingerprint.get(..16).and_then(|p| u64::from_str_radix(p, 16).ok())
+}
+
+/// Reads the optional More Keys and Key Print headers.
+/// Length must be multiple of 16, otherwise header is corrupt.
+fn extensions_from(extensions: &str)
+-> Result<(Vec<u32>, Option<u64>), Box<dyn std::error::Error + 'static>> {
+    let (more_keys, key_print) = match extensions.find(KEYPRINT_TAG) {
+        Some(i) => extensions.split_at(i),
+        None => (extensions, "")
+    };
+    Ok((more_keys_from(more_keys)?, key_print_header_from(key_print)?))
+}
+
+/// Reads the key_ids from More Keys header.
+/// Length must be multiple of 16, otherwise header is corrupt.
+fn more_keys_from(more_keys: &str)
+-> Result<Vec<u32>, Box<dyn std::error::Error + 'static>> {
+    let mut keys = Vec::new();
+    if more_keys.is_empty() {
+        return Ok(keys);
+    }
+    if ! more_keys.len().is_multiple_of(16) || more_keys.len() < 32 
+    || !more_keys.is_char_boundary(16) {
+        return Err("Corrupt More Keys header".into());
+    }
+    let (hdr, mut entries) = more_keys.split_at(16);
+    let (stag, pad) = hdr.split_at(8);
+    if u64::from_be_bytes(stag.as_bytes().try_into()?) != MOREKEYS_INT
+    || pad != MOREKEYS_PAD {
+        return Err("Corrupt More Keys header".into());
+    }
+    while !entries.is_empty() {
+        if !entries.is_char_boundary(16) {
+            return Err("Corrupt More Keys header".into());
+        }
+        let (entry, rest) = entries.split_at(16);
+        keys.push(u32::try_from(u64::from_str_radix(entry, 16)?)?);
+        entries = rest;
+    }
+    Ok(keys)
+}
+
+/// Reads the fingerprint from Key Print header.
+/// Length must be exactly 32, otherwise header is corrupt.
+fn key_print_header_from(key_print: &str)
+-> Result<Option<u64>, Box<dyn std::error::Error + 'static>> {
+    if key_print.is_empty() {
+        return Ok(None);
+    }
+    if key_print.len() != 32 || !key_print.is_char_boundary(16) {
+        return Err("Corrupt Key Print header".into());
+    }
+    let (hdr, entry) = key_print.split_at(16);
+    let (stag, pad) = hdr.split_at(8);
+    if u64::from_be_bytes(stag.as_bytes().try_into()?) != KEYPRINT_INT
+    || pad != MOREKEYS_PAD {
+        return Err("Corrupt Key Print header".into());
+    }
+    Ok(Some(u64::from_str_radix(entry, 16)?))
+}
+
 
@@ -1,2 +1,5 @@
 pub mod openssl;
 pub mod pgp;
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
+pub mod sym;
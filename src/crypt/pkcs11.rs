@@ -0,0 +1,175 @@
+use crate::crypt::openssl::{RsaScheme,ecc_decrypt_with,rsa_decrypt_with};
+use crate::guc::PKCS11_MODULE;
+use cryptoki::context::{CInitializeArgs,Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::mechanism::MechanismType;
+use cryptoki::mechanism::elliptic_curve::{EcKdf,Ecdh1DeriveParams};
+use cryptoki::mechanism::rsa::{PkcsMgfType,PkcsOaepParams,PkcsOaepSource};
+use cryptoki::object::{
+    Attribute,AttributeType,KeyType,ObjectClass,ObjectHandle
+};
+use cryptoki::session::{Session,UserType};
+use cryptoki::types::AuthPin;
+use once_cell::sync::OnceCell;
+use openssl::bn::BigNumContext;
+use openssl::ec::PointConversionForm;
+use openssl::pkey::{Id,PKey,Public};
+use pgrx::debug2;
+use std::sync::Mutex;
+
+/// PKCS#11 library, loaded and initialized once per backend
+static PKCS11: OnceCell<Pkcs11> = OnceCell::new();
+
+/// Private key kept in a PKCS#11 token. The key never leaves the token:
+/// RSA decryption and ECDH derivation are done by the token.
+pub struct Pkcs11Key {
+    /// logged in session, closed when the key is dropped
+    session: Mutex<Session>,
+    key: ObjectHandle,
+    key_type: KeyType,
+    label: String,
+}
+
+impl Pkcs11Key {
+    /// Finds the private key with `label` in the token in `slot_id`,
+    /// logging in with `pin`. Only sensitive keys that are not and never
+    /// were extractable are accepted.
+    pub fn new(slot_id: u64, label: &str, pin: &str)
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        let pkcs11 = PKCS11.get_or_try_init(pkcs11_init)?;
+        let slot = pkcs11.get_slots_with_token()?.into_iter()
+            .find(|s| s.id() == slot_id)
+            .ok_or(format!("No PKCS#11 token in slot {}", slot_id))?;
+        let session = pkcs11.open_ro_session(slot)?;
+        session.login(UserType::User, Some(&AuthPin::new(pin.into())))
+            .map_err(|e| format!("PKCS#11 login failed: {}", e))?;
+        let key = session.find_objects(&[
+                Attribute::Class(ObjectClass::PRIVATE_KEY),
+                Attribute::Label(label.as_bytes().to_vec())
+            ])?.into_iter().next()
+            .ok_or(format!("No PKCS#11 private key labeled '{}'", label))?;
+        let mut key_type = None;
+        let mut sensitive = false;
+        let mut never_extractable = false;
+        for attribute in session.get_attributes(key, &[
+            AttributeType::KeyType, AttributeType::Extractable,
+            AttributeType::Sensitive, AttributeType::NeverExtractable
+        ])? {
+            match attribute {
+                Attribute::KeyType(t) => key_type = Some(t),
+                Attribute::Extractable(true) => return Err(format!(
+                    "PKCS#11 private key '{}' is extractable", label).into()),
+                Attribute::Sensitive(s) => sensitive = s,
+                Attribute::NeverExtractable(n) => never_extractable = n,
+                _ => ()
+            }
+        }
+        if ! sensitive {
+            return Err(format!("PKCS#11 private key '{}' is not sensitive \
+                (CKA_SENSITIVE)", label).into());
+        }
+        if ! never_extractable {
+            return Err(format!("PKCS#11 private key '{}' was extractable \
+                (CKA_NEVER_EXTRACTABLE)", label).into());
+        }
+        let key_type = match key_type {
+            Some(t) if t == KeyType::RSA || t == KeyType::EC => t,
+            Some(t) => return Err(format!("Unsupported PKCS#11 key type \
+                {}", t).into()),
+            None => return Err("Unknown PKCS#11 key type".into())
+        };
+        debug2!("PKCS#11 {} private key '{}' in slot {}",
+            key_type, label, slot_id);
+        Ok(Pkcs11Key {
+            session: Mutex::new(session),
+            key,
+            key_type,
+            label: label.to_string()
+        })
+    }
+
+    pub fn is_rsa(&self) -> bool {
+        self.key_type == KeyType::RSA
+    }
+
+    pub fn is_ecc(&self) -> bool {
+        self.key_type == KeyType::EC
+    }
+
+    pub fn key_id(&self) -> String {
+        format!("PKCS#11 {} '{}'", self.key_type, self.label)
+    }
+
+    /// Same as `rsa_decrypt()` with the token RSA key
+    pub fn rsa_decrypt(&self, key_id: u32, msg: String)
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
+        rsa_decrypt_with(key_id, msg, |input, scheme| {
+            let session = self.session.lock()
+                .map_err(|e| format!("PKCS#11 session: {}", e))?;
+            Ok(session.decrypt(&rsa_mechanism(scheme), self.key, input)?)
+        })
+    }
+
+    /// Same as `ecc_decrypt()` with the token EC key (NIST curves)
+    pub fn ecc_decrypt(&self, msg: String)
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
+        ecc_decrypt_with(msg, |ephemeral| self.derive(ephemeral))
+    }
+
+    /// ECDH shared secret with the `ephemeral` public key, derived in the
+    /// token as a temporary session secret key
+    fn derive(&self, ephemeral: &PKey<Public>)
+    -> Result<Vec<u8>, Box<dyn std::error::Error + 'static>> {
+        if ephemeral.id() != Id::EC {
+            return Err("PKCS#11 keys only support NIST curves".into());
+        }
+        let ec_key = ephemeral.ec_key()?;
+        let mut ctx = BigNumContext::new()?;
+        let point = ec_key.public_key().to_bytes(ec_key.group(),
+            PointConversionForm::UNCOMPRESSED, &mut ctx)?;
+        let secret_len = (ec_key.group().degree() as u64 + 7) / 8;
+        let mechanism = Mechanism::Ecdh1Derive(
+            Ecdh1DeriveParams::new(EcKdf::null(), &point));
+        let session = self.session.lock()
+            .map_err(|e| format!("PKCS#11 session: {}", e))?;
+        let secret = session.derive_key(&mechanism, self.key, &[
+            Attribute::Class(ObjectClass::SECRET_KEY),
+            Attribute::KeyType(KeyType::GENERIC_SECRET),
+            Attribute::ValueLen(secret_len.into()),
+            Attribute::Token(false),
+            Attribute::Sensitive(false),
+            Attribute::Extractable(true),
+        ])?;
+        let value = session.get_attributes(secret, &[AttributeType::Value]);
+        session.destroy_object(secret)?;
+        match value?.into_iter().next() {
+            Some(Attribute::Value(shared)) => Ok(shared),
+            _ => Err("PKCS#11 ECDH derivation failed".into())
+        }
+    }
+}
+
+/// Loads `enigma.pkcs11_module`
+fn pkcs11_init() -> Result<Pkcs11, Box<dyn std::error::Error + 'static>> {
+    let module = match PKCS11_MODULE.get() {
+        Some(module) if ! module.is_empty() => module,
+        _ => return Err("enigma.pkcs11_module is not set".into())
+    };
+    let pkcs11 = Pkcs11::new(module.to_str()?)?;
+    pkcs11.initialize(CInitializeArgs::OsThreads)?;
+    Ok(pkcs11)
+}
+
+/// PKCS#11 mechanism for the RSA encryption scheme
+fn rsa_mechanism(scheme: RsaScheme) -> Mechanism<'static> {
+    match scheme {
+        RsaScheme::Pkcs1 => Mechanism::RsaPkcs,
+        RsaScheme::OaepSha1 => Mechanism::RsaPkcsOaep(PkcsOaepParams::new(
+            MechanismType::SHA1, PkcsMgfType::MGF1_SHA1,
+            PkcsOaepSource::empty())),
+        RsaScheme::OaepSha256 | RsaScheme::Envelope =>
+            Mechanism::RsaPkcsOaep(PkcsOaepParams::new(
+            MechanismType::SHA256, PkcsMgfType::MGF1_SHA256,
+            PkcsOaepSource::empty())),
+    }
+}
@@ -0,0 +1,212 @@
+use argon2::{Algorithm,Argon2,Params,Version};
+use crate::crypt::openssl::{
+    AES_KEY_LEN,BASE64_LINE_WRAP,GCM_IV_LEN,GCM_TAG_LEN,line_merge,line_wrap
+};
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead,KeyInit,Payload};
+use openssl::base64::{decode_block,encode_block};
+use openssl::hash::{MessageDigest,hash};
+use openssl::pkcs5::scrypt;
+use openssl::rand::rand_bytes;
+use openssl::symm::{Cipher,decrypt_aead,encrypt_aead};
+use pgrx::debug2;
+use std::collections::BTreeMap;
+
+/// Symmetric keys are 256 bits, both ciphers have 128 bit tags
+pub const SYM_KEY_LEN: usize = AES_KEY_LEN;
+const SYM_TAG_LEN: usize = GCM_TAG_LEN;
+const XCHACHA_NONCE_LEN: usize = 24;
+const KDF_SALT_LEN: usize = 16;
+const KEY_PRINT_CONTEXT: &[u8] = b"pg_enigma secret key print";
+// OWASP minimum for Argon2id: 19 MiB, 2 iterations, 1 lane
+const ARGON2_M_COST: u32 = 19456;
+const ARGON2_T_COST: u32 = 2;
+const ARGON2_P_COST: u32 = 1;
+// scrypt N = 2^15 (32 MiB with r = 8)
+const SCRYPT_LOG_N: u64 = 15;
+const SCRYPT_R: u64 = 8;
+const SCRYPT_P: u64 = 1;
+const SCRYPT_MAX_MEM: u64 = 256 * 1024 * 1024;
+// Bounds of the KDF parameters read from _enigma_secret_keys
+const ARGON2_MAX_M_COST: u64 = 256 * 1024; // 256 MiB
+const ARGON2_MAX_T_COST: u64 = 16;
+const ARGON2_MAX_P_COST: u64 = 16;
+const SCRYPT_MAX_R: u64 = 32;
+const SCRYPT_MAX_P: u64 = 16;
+
+/// Authenticated cipher of `PgE_SYM1` payloads. Its name is written in
+/// the first line of the payload.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymCipher {
+    Aes256Gcm,
+    XChaCha20Poly1305,
+}
+
+impl SymCipher {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SymCipher::Aes256Gcm => "AES-256-GCM",
+            SymCipher::XChaCha20Poly1305 => "XCHACHA20-POLY1305",
+        }
+    }
+
+    fn nonce_len(&self) -> usize {
+        match self {
+            SymCipher::Aes256Gcm => GCM_IV_LEN,
+            SymCipher::XChaCha20Poly1305 => XCHACHA_NONCE_LEN,
+        }
+    }
+}
+
+impl TryFrom<&str> for SymCipher {
+    type Error = Box<dyn std::error::Error + 'static>;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "AES-256-GCM" => Ok(SymCipher::Aes256Gcm),
+            "XCHACHA20-POLY1305" => Ok(SymCipher::XChaCha20Poly1305),
+            _ => Err(format!("Unknown symmetric cipher: {}", value).into())
+        }
+    }
+}
+
+/// Key derivation function for secret key passphrases
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kdf {
+    Argon2id,
+    Scrypt,
+}
+
+/// New KDF specification with the default parameters and a random salt,
+/// like `argon2id$m=19456,t=2,p=1$<base64 salt>`
+pub fn kdf_spec(kdf: Kdf)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    let mut salt = [0u8; KDF_SALT_LEN];
+    rand_bytes(&mut salt)?;
+    let salt = encode_block(&salt);
+    Ok(match kdf {
+        Kdf::Argon2id => format!("argon2id$m={},t={},p={}${}",
+            ARGON2_M_COST, ARGON2_T_COST, ARGON2_P_COST, salt),
+        Kdf::Scrypt => format!("scrypt$ln={},r={},p={}${}",
+            SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P, salt),
+    })
+}
+
+/// Derives the 256 bit key from the passphrase with the KDF `spec`
+pub fn kdf_derive(spec: &str, passphrase: &str)
+-> Result<Vec<u8>, Box<dyn std::error::Error + 'static>> {
+    let parts: Vec<&str> = spec.split('$').collect();
+    if parts.len() != 3 {
+        return Err(format!("Wrong KDF specification: {}", spec).into());
+    }
+    let params: BTreeMap<&str, u64> = parts[1].split(',')
+        .filter_map(|p| p.split_once('='))
+        .filter_map(|(k, v)| v.parse::<u64>().ok().map(|v| (k, v)))
+        .collect();
+    let param = |name: &str, max: u64| match params.get(name) {
+        Some(&value) if value <= max => Ok(value),
+        Some(value) => Err(format!("KDF parameter {} too large: {} \
+            (maximum {})", name, value, max)),
+        None => Err(format!("KDF parameter {} missing: {}", name, spec))
+    };
+    let salt = decode_block(parts[2])?;
+    let mut key = vec![0u8; SYM_KEY_LEN];
+    match parts[0] {
+        "argon2id" => {
+            let params = Params::new(param("m", ARGON2_MAX_M_COST)? as u32,
+                param("t", ARGON2_MAX_T_COST)? as u32,
+                param("p", ARGON2_MAX_P_COST)? as u32, Some(SYM_KEY_LEN))
+                .map_err(|e| format!("Argon2 parameters: {}", e))?;
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+                .map_err(|e| format!("Argon2: {}", e))?;
+        },
+        "scrypt" => {
+            let ln = param("ln", 24)?;
+            scrypt(passphrase.as_bytes(), &salt, 1 << ln,
+                param("r", SCRYPT_MAX_R)?, param("p", SCRYPT_MAX_P)?,
+                SCRYPT_MAX_MEM, &mut key)?;
+        },
+        kdf => return Err(format!("Unknown KDF: {}", kdf).into())
+    }
+    Ok(key)
+}
+
+/// First 64 bits of the SHA-256 of the key, for the Key Print header
+pub fn sym_key_print(key: &[u8])
+-> Result<u64, Box<dyn std::error::Error + 'static>> {
+    let mut input = KEY_PRINT_CONTEXT.to_vec();
+    input.extend_from_slice(key);
+    let digest = hash(MessageDigest::sha256(), &input);
+    input.fill(0);
+    let digest = digest?;
+    Ok(u64::from_be_bytes(digest[..8].try_into()?))
+}
+
+/// Encrypts the message with the 256 bit key.
+/// Payload layout: cipher name line, then base64 nonce, tag and
+/// ciphertext.
+pub fn sym_encrypt(key: &[u8], cipher: SymCipher, message: String)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    let mut nonce = vec![0u8; cipher.nonce_len()];
+    rand_bytes(&mut nonce)?;
+    let mut tag = [0u8; SYM_TAG_LEN];
+    let ciphertext = match cipher {
+        SymCipher::Aes256Gcm => encrypt_aead(Cipher::aes_256_gcm(), key,
+            Some(&nonce), &[], message.as_bytes(), &mut tag)?,
+        SymCipher::XChaCha20Poly1305 => {
+            let mut sealed = xchacha(key)?.encrypt(nonce.as_slice().into(),
+                Payload { msg: message.as_bytes(), aad: &[] })
+                .map_err(|_| "Symmetric encryption failed")?;
+            // sealed is ciphertext followed by the tag
+            let tag_at = sealed.len() - SYM_TAG_LEN;
+            tag.copy_from_slice(&sealed[tag_at..]);
+            sealed.truncate(tag_at);
+            sealed
+        }
+    };
+    let mut out = Vec::with_capacity(nonce.len() + SYM_TAG_LEN
+        + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&tag);
+    out.extend_from_slice(&ciphertext);
+    Ok(format!("{}\n{}", cipher.name(),
+        line_wrap(encode_block(&out), BASE64_LINE_WRAP)))
+}
+
+/// Decrypts the payload with the 256 bit key
+pub fn sym_decrypt(key: &[u8], msg: String)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    debug2!("Decrypt: SYM Enigma: {msg}");
+    let (cipher, body) = msg.split_once('\n')
+        .ok_or("Corrupted symmetric message")?;
+    let cipher = SymCipher::try_from(cipher)?;
+    let input = decode_block(line_merge(body.to_string()).as_str())?;
+    if input.len() < cipher.nonce_len() + SYM_TAG_LEN {
+        return Err("Corrupted symmetric message".into());
+    }
+    let (nonce, rest) = input.split_at(cipher.nonce_len());
+    let (tag, ciphertext) = rest.split_at(SYM_TAG_LEN);
+    let decrypted = match cipher {
+        SymCipher::Aes256Gcm => decrypt_aead(Cipher::aes_256_gcm(), key,
+            Some(nonce), &[], ciphertext, tag).ok(),
+        SymCipher::XChaCha20Poly1305 => {
+            let mut sealed = ciphertext.to_vec();
+            sealed.extend_from_slice(tag);
+            xchacha(key)?.decrypt(nonce.into(), 
+                Payload { msg: &sealed, aad: &[] }).ok()
+        }
+    }.ok_or("Symmetric decryption failed")?;
+    Ok(String::from_utf8(decrypted)?)
+}
+
+
+/*********************
+ * PRIVATE FUNCTIONS *
+ * *******************/
+
+fn xchacha(key: &[u8])
+-> Result<XChaCha20Poly1305, Box<dyn std::error::Error + 'static>> {
+    XChaCha20Poly1305::new_from_slice(key)
+        .map_err(|_| "Symmetric key must be 256 bits".into())
+}
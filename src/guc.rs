@@ -0,0 +1,281 @@
+use crate::crypt::openssl::RsaScheme;
+use crate::crypt::sym::{Kdf,SymCipher};
+use pgrx::guc::{GucContext,GucFlags,GucRegistry,GucSetting};
+use pgrx::PostgresGucEnum;
+use std::ffi::CString;
+
+/// RSA padding used for new single block RSA values
+#[derive(Clone, Copy, PartialEq, PostgresGucEnum)]
+pub enum RsaPadding {
+    #[name = c"oaep-sha256"]
+    OaepSha256,
+    #[name = c"oaep-sha1"]
+    OaepSha1,
+}
+
+impl From<RsaPadding> for RsaScheme {
+    fn from(value: RsaPadding) -> Self {
+        match value {
+            RsaPadding::OaepSha256 => RsaScheme::OaepSha256,
+            RsaPadding::OaepSha1 => RsaScheme::OaepSha1,
+        }
+    }
+}
+
+pub static RSA_PADDING: GucSetting<RsaPadding> = 
+    GucSetting::<RsaPadding>::new(RsaPadding::OaepSha256);
+
+/// Database where the jobs background worker runs `_enigma_jobs`
+pub static JOBS_DATABASE: GucSetting<Option<CString>> = 
+    GucSetting::<Option<CString>>::new(Some(c"postgres"));
+
+/// Seconds the jobs background worker sleeps when there are no jobs
+pub static JOBS_NAPTIME: GucSetting<i32> = GucSetting::<i32>::new(10);
+
+/// Days an expired PGP public key can still be used to encrypt
+pub static KEY_EXPIRY_GRACE: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// Warn when loading a PGP public key expiring within these days
+pub static KEY_EXPIRY_WARNING: GucSetting<i32> = GucSetting::<i32>::new(30);
+
+/// Seconds a private key is kept in memory, zero keeps it until forgotten
+pub static PRIVATE_KEY_TTL: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// Directory the `*_from_file()` functions can read key files from
+pub static KEY_DIRECTORY: GucSetting<Option<CString>> = 
+    GucSetting::<Option<CString>>::new(None);
+
+/// Command printing the passphrase of the preloaded private keys
+pub static PASSPHRASE_COMMAND: GucSetting<Option<CString>> = 
+    GucSetting::<Option<CString>>::new(None);
+
+/// Where the private keys in `enigma.key_directory` are loaded at start
+#[derive(Clone, Copy, PartialEq, PostgresGucEnum)]
+pub enum PreloadPrivateKeys {
+    #[name = c"off"]
+    Off,
+    #[name = c"backend"]
+    Backend,
+    #[name = c"vault"]
+    Vault,
+}
+
+pub static PRELOAD_PRIVATE_KEYS: GucSetting<PreloadPrivateKeys> = 
+    GucSetting::<PreloadPrivateKeys>::new(PreloadPrivateKeys::Off);
+
+/// PKCS#11 library for the private keys kept in tokens
+pub static PKCS11_MODULE: GucSetting<Option<CString>> = 
+    GucSetting::<Option<CString>>::new(None);
+
+/// Base URL of the key management service (Vault Transit API)
+pub static KMS_URL: GucSetting<Option<CString>> = 
+    GucSetting::<Option<CString>>::new(None);
+
+/// Token authenticating the session to the key management service
+pub static KMS_TOKEN: GucSetting<Option<CString>> = 
+    GucSetting::<Option<CString>>::new(None);
+
+/// HTTP header carrying `enigma.kms_token`
+pub static KMS_TOKEN_HEADER: GucSetting<Option<CString>> = 
+    GucSetting::<Option<CString>>::new(Some(c"X-Vault-Token"));
+
+/// Seconds to wait for the key management service
+pub static KMS_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(10);
+
+/// Cipher used for new `Esym` values
+#[derive(Clone, Copy, PartialEq, PostgresGucEnum)]
+pub enum SecretKeyCipher {
+    #[name = c"aes-256-gcm"]
+    Aes256Gcm,
+    #[name = c"xchacha20-poly1305"]
+    XChaCha20Poly1305,
+}
+
+impl From<SecretKeyCipher> for SymCipher {
+    fn from(value: SecretKeyCipher) -> Self {
+        match value {
+            SecretKeyCipher::Aes256Gcm => SymCipher::Aes256Gcm,
+            SecretKeyCipher::XChaCha20Poly1305 => SymCipher::XChaCha20Poly1305,
+        }
+    }
+}
+
+pub static SECRET_KEY_CIPHER: GucSetting<SecretKeyCipher> = 
+    GucSetting::<SecretKeyCipher>::new(SecretKeyCipher::Aes256Gcm);
+
+/// Key derivation function for new secret key passphrases
+#[derive(Clone, Copy, PartialEq, PostgresGucEnum)]
+pub enum SecretKeyKdf {
+    #[name = c"argon2id"]
+    Argon2id,
+    #[name = c"scrypt"]
+    Scrypt,
+}
+
+impl From<SecretKeyKdf> for Kdf {
+    fn from(value: SecretKeyKdf) -> Self {
+        match value {
+            SecretKeyKdf::Argon2id => Kdf::Argon2id,
+            SecretKeyKdf::Scrypt => Kdf::Scrypt,
+        }
+    }
+}
+
+pub static SECRET_KEY_KDF: GucSetting<SecretKeyKdf> = 
+    GucSetting::<SecretKeyKdf>::new(SecretKeyKdf::Argon2id);
+
+/// Registers pg_enigma GUCs. Called from `_PG_init()`
+pub fn init() {
+    GucRegistry::define_enum_guc(
+        c"enigma.rsa_padding",
+        c"RSA padding for new encrypted values",
+        c"OAEP digest used for values fitting in a single RSA block. \
+        Values encrypted with PKCS#1 padding can still be decrypted.",
+        &RSA_PADDING,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"enigma.jobs_database",
+        c"Database for the re-encryption jobs background worker",
+        c"The worker runs the jobs queued in this database's _enigma_jobs \
+        table. Requires pg_enigma in shared_preload_libraries.",
+        &JOBS_DATABASE,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"enigma.jobs_naptime",
+        c"Seconds between checks for new re-encryption jobs",
+        c"",
+        &JOBS_NAPTIME,
+        1,
+        3600,
+        GucContext::Sighup,
+        GucFlags::UNIT_S,
+    );
+    GucRegistry::define_int_guc(
+        c"enigma.key_expiry_grace",
+        c"Days an expired PGP public key can still encrypt",
+        c"Encryption to an expired key is refused after this grace period. \
+        Revoked keys are always refused.",
+        &KEY_EXPIRY_GRACE,
+        0,
+        36500,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"enigma.key_expiry_warning",
+        c"Warn about PGP public keys expiring within these days",
+        c"",
+        &KEY_EXPIRY_WARNING,
+        0,
+        36500,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"enigma.private_key_ttl",
+        c"Default time to live of private keys",
+        c"Expired private keys are forgotten when used again or at \
+        transaction end. Zero keeps them until forgotten or the session ends.",
+        &PRIVATE_KEY_TTL,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+    GucRegistry::define_string_guc(
+        c"enigma.key_directory",
+        c"Directory for key files",
+        c"Key and passphrase files read by the *_from_file() functions \
+        must be in this directory. Any readable path if not set.",
+        &KEY_DIRECTORY,
+        GucContext::Sighup,
+        GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_string_guc(
+        c"enigma.passphrase_command",
+        c"Command to obtain the passphrase of preloaded private keys",
+        c"%p is replaced by the key file path, %k by the key id and %% by \
+        %. The first line printed is the passphrase. Keys without \
+        passphrase are loaded if not set.",
+        &PASSPHRASE_COMMAND,
+        GucContext::Sighup,
+        GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_enum_guc(
+        c"enigma.preload_private_keys",
+        c"Loads the private keys in enigma.key_directory at start",
+        c"Files named <id>.pem or <id>.asc are loaded for every backend \
+        (backend) or in the shared memory private key vault (vault). \
+        Requires pg_enigma in shared_preload_libraries.",
+        &PRELOAD_PRIVATE_KEYS,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"enigma.pkcs11_module",
+        c"PKCS#11 library for private keys kept in tokens",
+        c"Used by set_private_key_pkcs11(). Requires pg_enigma built with \
+        the pkcs11 feature.",
+        &PKCS11_MODULE,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_string_guc(
+        c"enigma.kms_url",
+        c"Base URL of the key management service",
+        c"Data keys of kms:<key name> public keys are wrapped and unwrapped \
+        with POST <url>/encrypt/<key name> and <url>/decrypt/<key name> \
+        (Vault Transit API).",
+        &KMS_URL,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"enigma.kms_token",
+        c"Token for the key management service",
+        c"Values encrypted with kms:<key name> public keys can be decrypted \
+        if the key management service allows this token.",
+        &KMS_TOKEN,
+        GucContext::Userset,
+        GucFlags::NO_SHOW_ALL,
+    );
+    GucRegistry::define_string_guc(
+        c"enigma.kms_token_header",
+        c"HTTP header for enigma.kms_token",
+        c"",
+        &KMS_TOKEN_HEADER,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"enigma.kms_timeout",
+        c"Seconds to wait for the key management service",
+        c"",
+        &KMS_TIMEOUT,
+        1,
+        3600,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+    GucRegistry::define_enum_guc(
+        c"enigma.secret_key_cipher",
+        c"Cipher for new Esym values",
+        c"Values encrypted with the other cipher can still be decrypted.",
+        &SECRET_KEY_CIPHER,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        c"enigma.secret_key_kdf",
+        c"Key derivation function for new secret key passphrases",
+        c"Used the first time a passphrase is set for a secret key id. \
+        The function and its salt are kept in _enigma_secret_keys.",
+        &SECRET_KEY_KDF,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+}
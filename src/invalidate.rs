@@ -0,0 +1,79 @@
+use crate::PUB_KEYS;
+use crate::schema::extension_schema;
+use pgrx::prelude::*;
+use std::sync::atomic::{AtomicU32,Ordering};
+
+type RelcacheCallback = unsafe extern "C-unwind" fn(pg_sys::Datum, pg_sys::Oid);
+
+// utils/inval.h is not included in pgrx bindings
+extern "C-unwind" {
+    fn CacheRegisterRelcacheCallback(func: RelcacheCallback,
+        arg: pg_sys::Datum);
+    fn CacheInvalidateRelcacheByRelid(relid: pg_sys::Oid);
+}
+
+/// `_enigma_public_keys` oid, zero until resolved or the keys table is read
+static KEYS_RELID: AtomicU32 = AtomicU32::new(0);
+
+/// Registers the relcache callback dropping the cached public keys when 
+/// `_enigma_public_keys` changes in any session. Called from `_PG_init()`
+pub fn init() {
+    unsafe {
+        CacheRegisterRelcacheCallback(keys_relcache_callback, 
+            pg_sys::Datum::from(0usize));
+    }
+}
+
+/// Remembers the keys table oid, so only its invalidations drop the
+/// cached public keys.
+pub fn set_keys_relid(relid: pg_sys::Oid) {
+    KEYS_RELID.store(relid.to_u32(), Ordering::Relaxed);
+}
+
+/// Resolves the keys table oid on first use, so the invalidations of
+/// other relations do not drop the cached public keys.
+/// Must be called in a transaction.
+pub fn resolve_keys_relid() -> Result<(), pgrx::spi::Error> {
+    if KEYS_RELID.load(Ordering::Relaxed) != 0 {
+        return Ok(());
+    }
+    if let Some((schema, _)) = extension_schema()? {
+        let relid = unsafe {
+            pg_sys::get_relname_relid(c"_enigma_public_keys".as_ptr(), schema)
+        };
+        if relid != pg_sys::InvalidOid {
+            set_keys_relid(relid);
+        }
+    }
+    Ok(())
+}
+
+/// Keys table statement trigger. Invalidation is sent to all the backends
+/// when the transaction commits.
+#[pg_trigger]
+fn _enigma_public_keys_changed<'a>(trigger: &'a PgTrigger<'a>)
+-> Result<Option<PgHeapTuple<'a, AllocatedByPostgres>>, PgTriggerError> {
+    let relid = trigger.relid()?;
+    set_keys_relid(relid);
+    unsafe { CacheInvalidateRelcacheByRelid(relid) };
+    Ok(None)
+}
+
+/*********************
+ * PRIVATE FUNCTIONS *
+ * *******************/
+
+/// Marks the public keys map stale. Called while processing invalidation 
+/// messages, so it must not access the database: the keys are reloaded
+/// by the next `PubKeysMap::get()`.
+/// Any relation invalidates the keys until the keys table oid is resolved
+/// by the first `PubKeysMap::get()`.
+#[pg_guard]
+unsafe extern "C-unwind" fn keys_relcache_callback(_arg: pg_sys::Datum,
+relid: pg_sys::Oid) {
+    let keys_relid = KEYS_RELID.load(Ordering::Relaxed);
+    if keys_relid == 0 || relid == pg_sys::InvalidOid 
+    || relid.to_u32() == keys_relid {
+        PUB_KEYS.invalidate();
+    }
+}
@@ -0,0 +1,356 @@
+use crate::PRIV_KEYS;
+use crate::guc::{JOBS_DATABASE,JOBS_NAPTIME};
+use crate::key_file::check_key_path;
+use crate::rotate::{Batch,FIRST_CTID,check_column,rotate_batch};
+use pgrx::bgworkers::{
+    BackgroundWorker,BackgroundWorkerBuilder,SignalWakeFlags
+};
+use pgrx::datum::DatumWithOid;
+use pgrx::pg_sys::panic::CaughtError;
+use pgrx::prelude::*;
+use pgrx::spi::quote_identifier;
+use pgrx::PgRelation;
+use std::fs;
+use std::time::Duration;
+
+/// Queues a job re-encrypting `column` of table `rel` from `old_key_id` to
+/// `new_key_id`. The jobs background worker reads the private key from
+/// `key_file` and its passphrase from `pass_file`, keeping the key in
+/// memory only while running each batch. Files must be in 
+/// `enigma.key_directory` when it is set.
+#[pg_extern(volatile, requires = [ "enigma_jobs" ])]
+fn enigma_queue_rotation(rel: PgRelation, column: &str, old_key_id: i32,
+new_key_id: i32, key_file: &str, pass_file: default!(Option<&str>, "NULL"),
+batch_size: default!(i32, 1000), throttle_ms: default!(i32, 100))
+-> Result<Option<i32>, Box<dyn std::error::Error + 'static>> {
+    if ! unsafe { pg_sys::superuser() } {
+        return Err("Only superuser can queue re-encryption jobs".into());
+    }
+    if old_key_id < 1 {
+        return Err("Key id must be a positive integer".into());
+    }
+    if new_key_id < 0 {
+        return Err("Key id must be zero or greater".into());
+    }
+    if batch_size < 1 || throttle_ms < 0 {
+        return Err("Batch size must be positive and throttle \
+            must be zero or greater".into());
+    }
+    check_column(&rel, column)?;
+    check_key_path(key_file)?;
+    if let Some(f) = pass_file {
+        check_key_path(f)?;
+    }
+    let args = unsafe {
+        [
+            DatumWithOid::new(rel.oid(), PgBuiltInOids::OIDOID.value()),
+            DatumWithOid::new(column, PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(old_key_id, PgBuiltInOids::INT4OID.value()),
+            DatumWithOid::new(new_key_id, PgBuiltInOids::INT4OID.value()),
+            DatumWithOid::new(key_file, PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(pass_file, PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(batch_size, PgBuiltInOids::INT4OID.value()),
+            DatumWithOid::new(throttle_ms, PgBuiltInOids::INT4OID.value()),
+        ]
+    };
+    Ok(Spi::get_one_with_args(
+        r#"INSERT INTO _enigma_jobs(relid, column_name, old_key_id,
+            new_key_id, key_file, pass_file, batch_size, throttle_ms)
+           VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+           RETURNING id"#,
+        &args
+    )?)
+}
+
+/// Cancels a queued or running job
+#[pg_extern(volatile, requires = [ "enigma_jobs" ])]
+fn enigma_cancel_job(id: i32)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    let args = unsafe {
+        [ DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()) ]
+    };
+    let cancelled = Spi::get_one_with_args::<i32>(
+        r#"UPDATE _enigma_jobs
+           SET status = 'cancelled', updated_at = now(), finished_at = now()
+           WHERE id = $1 AND status IN ('queued', 'running')
+           RETURNING id"#,
+        &args
+    )?;
+    match cancelled {
+        Some(_) => Ok(format!("job {}: cancelled", id)),
+        None => Ok(format!("job {}: not queued or running", id))
+    }
+}
+
+/// Registers the jobs background worker.
+/// Only possible when loaded from `shared_preload_libraries`.
+pub fn init() {
+    if ! unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+    BackgroundWorkerBuilder::new("pg_enigma jobs worker")
+        .set_type("pg_enigma jobs worker")
+        .set_function("enigma_jobs_worker_main")
+        .set_library("pg_enigma")
+        .enable_spi_access()
+        .set_restart_time(Some(Duration::from_secs(10)))
+        .load();
+}
+
+/// Jobs background worker: runs one batch per transaction, sleeping
+/// `throttle_ms` between batches and `enigma.jobs_naptime` when idle.
+#[pg_guard]
+#[no_mangle]
+pub extern "C-unwind" fn enigma_jobs_worker_main(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(
+        SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    let database = JOBS_DATABASE.get()
+        .and_then(|db| db.into_string().ok())
+        .unwrap_or(String::from("postgres"));
+    BackgroundWorker::connect_worker_to_spi(Some(&database), None);
+    log!("pg_enigma jobs worker started on database {database}");
+
+    while BackgroundWorker::wait_latch(Some(naptime())) {
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext::PGC_SIGHUP) };
+        }
+        loop {
+            match BackgroundWorker::transaction(run_next_batch) {
+                Ok(Some(throttle)) => {
+                    if ! BackgroundWorker::wait_latch(Some(throttle)) {
+                        return;
+                    }
+                },
+                Ok(None) => break, // no jobs
+                Err(e) => {
+                    log!("pg_enigma jobs worker: {e}");
+                    break;
+                }
+            }
+        }
+    }
+    log!("pg_enigma jobs worker stopped");
+}
+
+/// Runs one batch of the oldest queued or running job and saves the
+/// checkpoint. A failing batch is rolled back and the job marked failed.
+/// Must be called inside a transaction.
+/// Returns the job throttle, `None` if there are no jobs.
+pub fn run_next_batch()
+-> Result<Option<Duration>, Box<dyn std::error::Error + 'static>> {
+    if ! Spi::get_one::<bool>(
+    "SELECT to_regclass('_enigma_jobs') IS NOT NULL")?.unwrap_or(false) {
+        return Ok(None); // pg_enigma not installed
+    }
+    let job = match Job::next()? {
+        Some(j) => j,
+        None => return Ok(None)
+    };
+    let result = job.load_key()
+        .map_err(|e| e.to_string())
+        .and_then(|_| in_subtransaction(|| job.run_batch()));
+    job.forget_key()?;
+    match result {
+        Ok(batch) => job.checkpoint(batch)?,
+        Err(e) => {
+            log!("pg_enigma job {}: {}", job.id, e);
+            job.fail(&e)?;
+        }
+    }
+    Ok(Some(Duration::from_millis(job.throttle_ms as u64)))
+}
+
+/*********************
+ * PRIVATE FUNCTIONS *
+ * *******************/
+
+/// Row of `_enigma_jobs`
+struct Job {
+    id: i32,
+    /// regclass as text, quoted as needed
+    table: String,
+    /// quoted column name
+    column: String,
+    old_key_id: i32,
+    new_key_id: i32,
+    key_file: String,
+    pass_file: Option<String>,
+    batch_size: i32,
+    throttle_ms: i32,
+    last_ctid: Option<String>,
+    queued: bool
+}
+
+impl Job {
+    /// Oldest queued or running job, locked until the transaction ends
+    fn next() -> Result<Option<Self>, Box<dyn std::error::Error + 'static>> {
+        Spi::connect_mut(|client| {
+            let mut table = client.update(
+                r#"SELECT id, relid::text AS table_name,
+                    column_name::text AS column_name, old_key_id, new_key_id,
+                    key_file, pass_file, batch_size, throttle_ms,
+                    last_ctid::text AS last_ctid, status
+                   FROM _enigma_jobs
+                   WHERE status IN ('queued', 'running')
+                   ORDER BY id LIMIT 1
+                   FOR UPDATE SKIP LOCKED"#,
+                None, &[])?;
+            let row = match table.next() {
+                Some(r) => r,
+                None => return Ok(None)
+            };
+            let column: String = row.get_by_name("column_name")?
+                .ok_or("Job without column")?;
+            let status: String = row.get_by_name("status")?
+                .ok_or("Job without status")?;
+            Ok(Some(Job {
+                id: row.get_by_name("id")?.ok_or("Job without id")?,
+                table: row.get_by_name("table_name")?
+                    .ok_or("Job without table")?,
+                column: quote_identifier(column),
+                old_key_id: row.get_by_name("old_key_id")?
+                    .ok_or("Job without old key id")?,
+                new_key_id: row.get_by_name("new_key_id")?
+                    .ok_or("Job without new key id")?,
+                key_file: row.get_by_name("key_file")?
+                    .ok_or("Job without key file")?,
+                pass_file: row.get_by_name("pass_file")?,
+                batch_size: row.get_by_name("batch_size")?.unwrap_or(1000),
+                throttle_ms: row.get_by_name("throttle_ms")?.unwrap_or(100),
+                last_ctid: row.get_by_name("last_ctid")?,
+                queued: status == "queued"
+            }))
+        })
+    }
+
+    /// Reads the private key and its passphrase from the job files
+    fn load_key(&self) -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let key = fs::read_to_string(&self.key_file)?;
+        let pass = match &self.pass_file {
+            Some(f) => fs::read_to_string(f)?
+                .trim_end_matches(['\r', '\n']).to_string(),
+            None => String::new()
+        };
+        let msg = PRIV_KEYS.set(self.old_key_id as u32, &key, &pass, None)?;
+        debug1!("pg_enigma job {}: {}", self.id, msg);
+        Ok(())
+    }
+
+    fn forget_key(&self) -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let msg = PRIV_KEYS.del(self.old_key_id as u32)?;
+        debug1!("pg_enigma job {}: {}", self.id, msg);
+        Ok(())
+    }
+
+    /// Counts the values to re-encrypt when the job starts, then
+    /// re-encrypts the next batch after the checkpoint
+    fn run_batch(&self) -> Result<Batch, Box<dyn std::error::Error + 'static>> {
+        if self.queued {
+            let args = unsafe {
+                [
+                    DatumWithOid::new(self.id, PgBuiltInOids::INT4OID.value()),
+                    DatumWithOid::new(self.new_key_id,
+                        PgBuiltInOids::INT4OID.value()),
+                    DatumWithOid::new(self.old_key_id,
+                        PgBuiltInOids::INT4OID.value()),
+                ]
+            };
+            Spi::run_with_args(&format!(
+                r#"UPDATE _enigma_jobs
+                   SET status = 'running', started_at = now(),
+                   remaining = (SELECT count(*) FROM {table}
+                                WHERE enigma_key_id({column}) <> $2
+                                AND enigma_key_id({column}) = $3)
+                   WHERE id = $1"#, table = self.table, column = self.column),
+                &args)?;
+        }
+        rotate_batch(&self.table, &self.column, self.new_key_id,
+            Some(self.old_key_id), self.batch_size,
+            self.last_ctid.as_deref().unwrap_or(FIRST_CTID))
+    }
+
+    /// Saves the batch checkpoint. An empty batch after the checkpoint
+    /// starts a last pass from the first tuple; an empty pass from the
+    /// first tuple finishes the job.
+    fn checkpoint(&self, batch: Batch)
+    -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let args = unsafe {
+            [
+                DatumWithOid::new(self.id, PgBuiltInOids::INT4OID.value()),
+                DatumWithOid::new(batch.rotated,
+                    PgBuiltInOids::INT8OID.value()),
+                DatumWithOid::new(batch.last_ctid.clone(),
+                    PgBuiltInOids::TEXTOID.value()),
+            ]
+        };
+        let query = match (&batch.last_ctid, &self.last_ctid) {
+            (None, None) => r#"UPDATE _enigma_jobs
+                SET status = 'done', remaining = 0,
+                updated_at = now(), finished_at = now()
+                WHERE id = $1"#,
+            _ => r#"UPDATE _enigma_jobs
+                SET last_ctid = $3::tid, reencrypted = reencrypted + $2,
+                remaining = greatest(remaining - $2, 0), updated_at = now()
+                WHERE id = $1"#
+        };
+        Spi::run_with_args(query, &args)?;
+        Ok(())
+    }
+
+    fn fail(&self, error: &str)
+    -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let args = unsafe {
+            [
+                DatumWithOid::new(self.id, PgBuiltInOids::INT4OID.value()),
+                DatumWithOid::new(error, PgBuiltInOids::TEXTOID.value()),
+            ]
+        };
+        Spi::run_with_args(
+            r#"UPDATE _enigma_jobs
+               SET status = 'failed', error = $2,
+               updated_at = now(), finished_at = now()
+               WHERE id = $1"#,
+            &args
+        )?;
+        Ok(())
+    }
+}
+
+/// `enigma.jobs_naptime` as `Duration`
+fn naptime() -> Duration {
+    Duration::from_secs(JOBS_NAPTIME.get() as u64)
+}
+
+/// Runs `f` in a subtransaction, so an error raised by Postgres rolls back
+/// only the batch and the job can still be marked as failed.
+fn in_subtransaction<F>(f: F) -> Result<Batch, String>
+where F: FnOnce() -> Result<Batch, Box<dyn std::error::Error + 'static>>
++ std::panic::UnwindSafe {
+    unsafe {
+        let context = pg_sys::CurrentMemoryContext;
+        let owner = pg_sys::CurrentResourceOwner;
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        PgTryBuilder::new(move || {
+            let result = f().map_err(|e| e.to_string());
+            match result {
+                Ok(_) => pg_sys::ReleaseCurrentSubTransaction(),
+                Err(_) => pg_sys::RollbackAndReleaseCurrentSubTransaction()
+            }
+            pg_sys::MemoryContextSwitchTo(context);
+            pg_sys::CurrentResourceOwner = owner;
+            result
+        })
+        .catch_others(move |e| {
+            pg_sys::RollbackAndReleaseCurrentSubTransaction();
+            pg_sys::MemoryContextSwitchTo(context);
+            pg_sys::CurrentResourceOwner = owner;
+            Err(match e {
+                CaughtError::PostgresError(r)
+                | CaughtError::ErrorReport(r)
+                | CaughtError::RustPanic { ereport: r, .. } =>
+                    r.message().to_string()
+            })
+        })
+        .execute()
+    }
+}
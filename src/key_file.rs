@@ -0,0 +1,65 @@
+use crate::guc::KEY_DIRECTORY;
+use pgrx::pg_sys;
+use std::fs;
+use std::path::{Path,PathBuf};
+
+/// Reads the key (or passphrase) file for the `*_from_file()` functions.
+/// See `check_key_path()`.
+pub fn read_key_file(file_path: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    let path = check_key_path(file_path)?;
+    fs::read_to_string(&path).map_err(|e| 
+        format!("Error reading key file {}: {}", file_path, e).into())
+}
+
+/// Same as `read_key_file()` for binary (DER) key files
+pub fn read_key_file_bytes(file_path: &str)
+-> Result<Vec<u8>, Box<dyn std::error::Error + 'static>> {
+    let path = check_key_path(file_path)?;
+    fs::read(&path).map_err(|e| 
+        format!("Error reading key file {}: {}", file_path, e).into())
+}
+
+/// Only superusers and members of `pg_read_server_files` can read key
+/// files, and only inside `enigma.key_directory` when it is set.
+/// Returns the canonical path of the file.
+pub fn check_key_path(file_path: &str)
+-> Result<PathBuf, Box<dyn std::error::Error + 'static>> {
+    if ! can_read_server_files() {
+        return Err("Only superusers and members of pg_read_server_files \
+            can read key files".into());
+    }
+    let path = Path::new(file_path).canonicalize().map_err(|e| 
+        format!("Error reading key file {}: {}", file_path, e))?;
+    if let Some(dir) = key_directory()? {
+        if ! path.starts_with(&dir) {
+            return Err(format!("Key file {} is not in enigma.key_directory", 
+                file_path).into());
+        }
+    }
+    Ok(path)
+}
+
+/// Canonical `enigma.key_directory`, `None` if not set
+pub fn key_directory() 
+-> Result<Option<PathBuf>, Box<dyn std::error::Error + 'static>> {
+    match KEY_DIRECTORY.get() {
+        Some(dir) if ! dir.is_empty() => {
+            let dir = dir.to_str()?;
+            Ok(Some(Path::new(dir).canonicalize().map_err(|e| 
+                format!("Wrong enigma.key_directory {}: {}", dir, e))?))
+        },
+        _ => Ok(None)
+    }
+}
+
+/*********************
+ * PRIVATE FUNCTIONS *
+ * *******************/
+
+fn can_read_server_files() -> bool {
+    unsafe {
+        pg_sys::superuser() || pg_sys::has_privs_of_role(pg_sys::GetUserId(),
+            pg_sys::get_role_oid(c"pg_read_server_files".as_ptr(), true))
+    }
+}
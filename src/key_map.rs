@@ -1,21 +1,45 @@
+use crate::PRIV_KEYS;
+use crate::invalidate::resolve_keys_relid;
 use crate::priv_key::PrivKey;
 use crate::pub_key::{PubKey,get_public_key};
-use pgrx::{debug1,info};
+use crate::secret_key::SecretKey;
+use crate::vault;
+use core::ffi::c_void;
+use pgrx::{
+    debug1,info,pg_guard,pg_sys,register_xact_callback,warning,
+    PgXactCallbackEvent
+};
 use std::collections::BTreeMap;
 use std::mem::drop;
-use std::sync::RwLock;
+use std::sync::{Arc,RwLock};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::time::{Duration,Instant};
 
 /********************
  * Private keys map *
  * ******************/
 pub struct PrivKeysMap {
-    /// each `BTreeMap` entry is a reference to a `PrivKey` structure
-    keys: RwLock<BTreeMap<u32,&'static PrivKey>>,
+    /// each `BTreeMap` entry is a shared reference to a `PrivKey` structure
+    keys: RwLock<BTreeMap<u32,PrivKeyEntry>>,
+}
+
+/// Private key and the instant it expires, if any
+struct PrivKeyEntry {
+    key: Arc<PrivKey>,
+    expires: Option<Instant>,
+    /// forgotten at transaction end
+    local: bool,
+}
+
+impl PrivKeyEntry {
+    fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|e| e <= Instant::now())
+    }
 }
 
 /// Functions for private keys map
-/// Lifetimes are handled here, so these functions can be called safely
-/// from elsewhere.
+/// Replaced or removed keys are dropped (and zeroized) as soon as 
+/// the last reference returned by `get()` goes out of scope.
 impl PrivKeysMap {
     /// Creates new (empty) PrivKeys struct
     pub fn new() -> Self {
@@ -26,28 +50,57 @@ impl PrivKeysMap {
     }
 
     /// Sets the `PrivKeysMap` `id` to the `PrivKey` obtained from the
-    /// provides armored key and plain text password
-    pub fn set(&self, id: u32, armored_key: &str, pw: &str)
+    /// provides armored key and plain text password.
+    /// The key is forgotten once its time to live `ttl` lapses.
+    pub fn set(&self, id: u32, armored_key: &str, pw: &str, 
+    ttl: Option<Duration>)
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
+        self.insert(id, PrivKey::new(armored_key, pw)?, ttl, false)
+    }
+
+    /// Sets the `PrivKeysMap` `id` to the already parsed `PrivKey`
+    pub fn set_key(&self, id: u32, key: PrivKey, ttl: Option<Duration>)
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
+        self.insert(id, key, ttl, false)
+    }
+
+    /// Same as `set()`, but the key is forgotten when the current 
+    /// transaction commits, aborts or is prepared
+    pub fn set_local(&'static self, id: u32, armored_key: &str, pw: &str)
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
+        let msg = self.insert(id, PrivKey::new(armored_key, pw)?, None, 
+            true)?;
+        register_xact_callback(PgXactCallbackEvent::Commit, 
+            move || self.forget_local(id));
+        register_xact_callback(PgXactCallbackEvent::Abort, 
+            move || self.forget_local(id));
+        register_xact_callback(PgXactCallbackEvent::Prepare, 
+            move || self.forget_local(id));
+        Ok(format!("{} until transaction end", msg))
+    }
+
+    fn insert(&self, id: u32, key: PrivKey, ttl: Option<Duration>, 
+    local: bool)
     -> Result<String, Box<dyn std::error::Error + 'static>> {
-        let key = PrivKey::new(armored_key, pw)?; // key with '1 lifetime
         let priv_id = key.priv_key_id();
-        // put the key into the box to allow change it's lifetime
-        let boxed_key = Box::new(key);
-        // leaked key is the same address, but now with 'static lifetime
-        let static_key: &'static PrivKey = Box::leak(boxed_key);
+        let entry = PrivKeyEntry {
+            key: Arc::new(key),
+            expires: ttl.map(|t| Instant::now() + t),
+            local
+        };
         // need write lock to insert the key on the BTreeMap
         let old = match self.keys.write() {
             // RwLock::insert() returns Some(old_value) if replaced
-            Ok(mut m) => m.insert(id, &static_key),
+            Ok(mut m) => m.insert(id, entry),
             Err(e) => return Err(
                 format!("PrivKeysMap: set: could not get write lock: {}", e)
                 .into()),
         };
         
-        let msg = match old {
+        let mut msg = match old {
             Some(o) => { // the old key was replaced
-                let old_id = o.priv_key_id(); 
-                // TODO: drop(o); // free old key (explicitly)
+                let old_id = o.key.priv_key_id(); 
+                drop(o); // free old key (explicitly)
                 format!("key {}: private key {} replaced with {}", 
                     id, old_id, priv_id)
             },
@@ -55,12 +108,15 @@ impl PrivKeysMap {
                 format!("key {}: private key {} imported", id, priv_id)
             }
         };
+        if let Some(t) = ttl {
+            msg.push_str(&format!(", expires in {} s", t.as_secs()));
+        }
         Ok(msg)
     }
     
     /// Removes key from the `PrivKeysMap`. 
-    /// Once the key gets out of scope, it's supposed to be dropped.
-    pub fn del(&'static self, id: u32) 
+    /// The key is dropped once the last reference gets out of scope.
+    pub fn del(&self, id: u32) 
     -> Result<String, Box<dyn std::error::Error + 'static>> {
         let old = match self.keys.write() {
             Ok(mut m) => {
@@ -73,8 +129,8 @@ impl PrivKeysMap {
 
         let msg = match old {
             Some(o) => {
-                let priv_id = o.priv_key_id();
-                // TODO: drop(o); // free old key (explicitly)
+                let priv_id = o.key.priv_key_id();
+                drop(o); // free old key (explicitly)
                 format!("key {}: private key {} forgotten", id, priv_id)
             },
             None => format!("key {}: not set", id)
@@ -82,16 +138,65 @@ impl PrivKeysMap {
         Ok(msg)
     }
 
-    /// Gets reference to `PrivKey` from `PrivKeysMap` entry with `id` 
-    pub fn get(self: &'static PrivKeysMap, id: u32) 
-    -> Result<Option<&'static PrivKey>, 
-    Box<dyn std::error::Error + 'static>> {
+    /// Gets reference to `PrivKey` from `PrivKeysMap` entry with `id`.
+    /// Expired keys are forgotten.
+    pub fn get(&self, id: u32) 
+    -> Result<Option<Arc<PrivKey>>, Box<dyn std::error::Error + 'static>> {
         let binding = self.keys.read()?;
-        let key = match binding.get(&id) {
-            Some(k) => k,
-            None => return Ok(None)
-        };
-        Ok(Some(key))
+        match binding.get(&id) {
+            Some(entry) if entry.is_expired() => {
+                drop(binding);
+                debug1!("{} (expired)", self.del(id)?);
+                vault::get(id)
+            },
+            Some(entry) => Ok(Some(Arc::clone(&entry.key))),
+            None => {
+                drop(binding);
+                vault::get(id) // keys shared by all backends
+            }
+        }
+    }
+
+    /// Forgets the key `id` if it was set for the transaction only
+    fn forget_local(&self, id: u32) {
+        match self.keys.write() {
+            Ok(mut m) => {
+                if m.get(&id).is_some_and(|entry| entry.local) {
+                    m.remove(&id);
+                }
+            },
+            Err(e) => warning!("PrivKeysMap: key {}: could not forget \
+                transaction key: {}", id, e)
+        }
+    }
+
+    /// Forgets all the expired keys
+    pub fn forget_expired(&self) {
+        // never wait for the lock at transaction end
+        if let Ok(mut m) = self.keys.try_write() {
+            m.retain(|_, entry| ! entry.is_expired());
+        }
+    }
+}
+
+/// Forgets the expired private keys at transaction end, 
+/// even if they are not used again
+#[pg_guard]
+unsafe extern "C-unwind" fn forget_expired_keys(event: pg_sys::XactEvent::Type,
+_arg: *mut c_void) {
+    if event == pg_sys::XactEvent::XACT_EVENT_COMMIT
+    || event == pg_sys::XactEvent::XACT_EVENT_ABORT
+    || event == pg_sys::XactEvent::XACT_EVENT_PREPARE {
+        PRIV_KEYS.forget_expired();
+    }
+}
+
+/// Registers the transaction callback forgetting expired private keys. 
+/// Called from `_PG_init()`
+pub fn init() {
+    unsafe {
+        pg_sys::RegisterXactCallback(Some(forget_expired_keys), 
+            std::ptr::null_mut());
     }
 }
 
@@ -99,19 +204,22 @@ impl PrivKeysMap {
  * Public keys map *
  * *****************/
 pub struct PubKeysMap {
-    /// each `BTreeMap` entry is a reference to a `PubKey` structure
-    keys: RwLock<BTreeMap<u32,&'static PubKey>>,
+    /// each `BTreeMap` entry is a shared reference to a `PubKey` structure
+    keys: RwLock<BTreeMap<u32,Arc<PubKey>>>,
+    /// keys table changed, keys must be reloaded
+    stale: AtomicBool,
 }
 
-/// Functions for private keys map
-/// Lifetimes are handled here, so these functions can be called safely
-/// from elsewhere.
+/// Functions for public keys map
+/// Replaced or removed keys are dropped as soon as the last reference
+/// returned by `get()` goes out of scope.
 impl PubKeysMap {
     /// Creates new (empty) PubKeys struct
     pub fn new() -> Self {
         let keys = RwLock::new(BTreeMap::new());
         PubKeysMap {
-            keys: keys // new empty BTreeMap
+            keys: keys, // new empty BTreeMap
+            stale: AtomicBool::new(false)
         }
     }
 
@@ -119,16 +227,17 @@ impl PubKeysMap {
     /// provides armored key and plain text password
     pub fn set(&self, id: u32, armored_key: &str)
     -> Result<String, Box<dyn std::error::Error + 'static>> {
-        let key = PubKey::new(armored_key)?; // key with '1 lifetime
+        self.set_key(id, PubKey::new(armored_key)?)
+    }
+
+    /// Sets the `PubKeysMap` `id` to the already parsed `PubKey`
+    pub fn set_key(&self, id: u32, key: PubKey)
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
         let pub_id = key.pub_key_id();
-        // put the key into the box to allow change it's lifetime
-        let boxed_key = Box::new(key);
-        // leaked key is the same address, but now with 'static lifetime
-        let static_key: &'static PubKey = Box::leak(boxed_key);
         // need write lock to insert the key on the BTreeMap
         let old = match self.keys.write() {
             // RwLock::insert() returns Some(old_value) if replaced
-            Ok(mut m) => m.insert(id, &static_key),
+            Ok(mut m) => m.insert(id, Arc::new(key)),
             Err(e) => return Err(
                 format!("PubKeysMap: set: could not get write lock: {}", e)
                 .into()),
@@ -137,7 +246,7 @@ impl PubKeysMap {
         let msg = match old {
             Some(o) => { // the old key was replaced
                 let old_id = o.pub_key_id(); 
-                // TODO: drop(o); // free old key (explicitly)
+                drop(o); // free old key (explicitly)
                 format!("key {}: public key {} replaced with {}", 
                     id, old_id, pub_id)
             },
@@ -149,8 +258,8 @@ impl PubKeysMap {
     }
 
     /// Removes key from the `PubKeysMap`. 
-    /// Once the key gets out of scope, it's supposed to be dropped.
-    pub fn del(&'static self, id: u32) 
+    /// The key is dropped once the last reference gets out of scope.
+    pub fn del(&self, id: u32) 
     -> Result<String, Box<dyn std::error::Error + 'static>> {
         let old = match self.keys.write() {
             Ok(mut m) => {
@@ -164,7 +273,7 @@ impl PubKeysMap {
         let msg = match old {
             Some(o) => {
                 let pub_id = o.pub_key_id();
-                // TODO: drop(o); // free old key (explicitly)
+                drop(o); // free old key (explicitly)
                 format!("key {}: public key {} forgotten", id, pub_id)
             },
             None => format!("key {}: not set", id)
@@ -172,13 +281,28 @@ impl PubKeysMap {
         Ok(msg)
     }
 
+    /// Marks all the keys stale, e.g. when another session changed the
+    /// keys table. Keys are reloaded from the table by `get()`.
+    pub fn invalidate(&self) {
+        self.stale.store(true, Ordering::Relaxed);
+    }
+
     /// Gets reference to `PubKey` from `PubKeysMap` entry with `id` 
-    pub fn get(self: &'static PubKeysMap, id: u32) 
-    -> Result<Option<&'static PubKey>, 
-    Box<dyn std::error::Error + 'static>> {
+    pub fn get(&self, id: u32) 
+    -> Result<Option<Arc<PubKey>>, Box<dyn std::error::Error + 'static>> {
+        resolve_keys_relid()?;
+        if self.stale.swap(false, Ordering::Relaxed) {
+            debug1!("PubKeysMap: keys table changed, reloading keys");
+            match self.keys.write() {
+                Ok(mut m) => m.clear(), // old keys are dropped
+                Err(e) => return Err(
+                    format!("PubKeysMap: get: could not get write lock: {}", 
+                    e).into()),
+            }
+        }
         let binding = self.keys.read()?;
         let key = match binding.get(&id) {
-            Some(k) => k,
+            Some(k) => Arc::clone(k),
             None => {
                 drop(binding);
                 // get_public_key() reads Key from SQL
@@ -198,3 +322,91 @@ impl PubKeysMap {
 
 }
 
+
+/*******************
+ * Secret keys map *
+ * *****************/
+pub struct SecretKeysMap {
+    /// each `BTreeMap` entry is a shared reference to a `SecretKey`
+    keys: RwLock<BTreeMap<u32,Arc<SecretKey>>>,
+}
+
+/// Functions for secret (symmetric) keys map
+/// Replaced or removed keys are dropped (and zeroized) as soon as 
+/// the last reference returned by `get()` goes out of scope.
+impl SecretKeysMap {
+    /// Creates new (empty) SecretKeys struct
+    pub fn new() -> Self {
+        SecretKeysMap {
+            keys: RwLock::new(BTreeMap::new())
+        }
+    }
+
+    /// Sets the `SecretKeysMap` `id` to the `SecretKey` obtained from the
+    /// provided key or passphrase.
+    /// Keys with a new KDF salt are forgotten if the transaction aborts,
+    /// since the salt is not stored.
+    pub fn set(&'static self, id: u32, key_or_passphrase: &str)
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
+        let key = SecretKey::new(id, key_or_passphrase)?;
+        let sec_id = key.secret_key_id();
+        if key.is_new_kdf() {
+            register_xact_callback(PgXactCallbackEvent::Abort, 
+                move || self.forget_new(id));
+        }
+        let old = match self.keys.write() {
+            Ok(mut m) => m.insert(id, Arc::new(key)),
+            Err(e) => return Err(
+                format!("SecretKeysMap: set: could not get write lock: {}", 
+                e).into()),
+        };
+        let msg = match old {
+            Some(o) => {
+                let old_id = o.secret_key_id();
+                drop(o); // free old key (explicitly)
+                format!("key {}: secret key {} replaced with {}", 
+                    id, old_id, sec_id)
+            },
+            None => format!("key {}: secret key {} imported", id, sec_id)
+        };
+        Ok(msg)
+    }
+
+    /// Removes key from the `SecretKeysMap`. 
+    /// The key is dropped once the last reference gets out of scope.
+    pub fn del(&self, id: u32) 
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
+        let old = match self.keys.write() {
+            Ok(mut m) => m.remove(&id),
+            Err(e) => return Err(
+                format!("SecretKeysMap: del: could not get write lock: {}", 
+                e).into()),
+        };
+        let msg = match old {
+            Some(o) => {
+                let sec_id = o.secret_key_id();
+                drop(o); // free old key (explicitly)
+                format!("key {}: secret key {} forgotten", id, sec_id)
+            },
+            None => format!("key {}: not set", id)
+        };
+        Ok(msg)
+    }
+
+    /// Gets reference to `SecretKey` from `SecretKeysMap` entry with `id`
+    pub fn get(&self, id: u32) 
+    -> Result<Option<Arc<SecretKey>>, Box<dyn std::error::Error + 'static>> {
+        Ok(self.keys.read()?.get(&id).map(Arc::clone))
+    }
+
+    /// Forgets the key `id` if its KDF salt was created in the aborted
+    /// transaction
+    fn forget_new(&self, id: u32) {
+        // never wait for the lock at transaction end
+        if let Ok(mut m) = self.keys.try_write() {
+            if m.get(&id).is_some_and(|key| key.is_new_kdf()) {
+                m.remove(&id);
+            }
+        }
+    }
+}
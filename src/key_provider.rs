@@ -0,0 +1,239 @@
+use crate::common::{Decrypt,Plain};
+use crate::crypt::openssl::{AES_KEY_LEN,kms_decrypt};
+use crate::guc::{KMS_TIMEOUT,KMS_TOKEN,KMS_TOKEN_HEADER,KMS_URL};
+use crate::types::enigma::Enigma;
+use once_cell::sync::Lazy;
+use openssl::base64::{decode_block,encode_block};
+use openssl::rand::rand_bytes;
+use pgrx::{check_for_interrupts,debug2,register_xact_callback};
+use pgrx::PgXactCallbackEvent;
+use serde_json::{json,Value};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use zeroize::{Zeroize,Zeroizing};
+
+/// Public keys starting with this prefix reference a KMS key:
+/// `kms:<key name>`
+pub const KMS_KEY_PREFIX: &str = "kms:";
+
+/// Wraps and unwraps data keys with keys kept in an external key
+/// management service (KMS). The KMS keys never enter Postgres.
+pub trait KeyProvider {
+    /// `data_key` wrapped with the KMS key `key_ref`
+    fn wrap_key(&self, key_ref: &str, data_key: &[u8])
+    -> Result<String, Box<dyn std::error::Error + 'static>>;
+
+    /// Data key unwrapped with the KMS key `key_ref`
+    fn unwrap_key(&self, key_ref: &str, wrapped: &str)
+    -> Result<Vec<u8>, Box<dyn std::error::Error + 'static>>;
+
+    /// KMS and credentials: cached data keys are only used with the same
+    fn cache_id(&self) -> String;
+}
+
+/// Generic HTTP provider speaking the Vault Transit API:
+/// `POST <url>/encrypt/<key>` and `POST <url>/decrypt/<key>`.
+/// Configured with the `enigma.kms_*` settings.
+pub struct HttpKeyProvider {
+    url: String,
+    token: Option<String>,
+    token_header: String,
+    agent: ureq::Agent
+}
+
+impl HttpKeyProvider {
+    /// Provider for `enigma.kms_url`. `None` if it is not set.
+    pub fn from_settings()
+    -> Result<Option<Self>, Box<dyn std::error::Error + 'static>> {
+        let url = match KMS_URL.get() {
+            Some(url) if ! url.is_empty() => url.to_str()?
+                .trim_end_matches('/').to_string(),
+            _ => return Ok(None)
+        };
+        let token = match KMS_TOKEN.get() {
+            Some(token) if ! token.is_empty() =>
+                Some(token.to_str()?.to_string()),
+            _ => None
+        };
+        let token_header = match KMS_TOKEN_HEADER.get() {
+            Some(header) => header.to_str()?.to_string(),
+            None => String::new()
+        };
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(KMS_TIMEOUT.get() as u64))
+            .build();
+        Ok(Some(HttpKeyProvider { url, token, token_header, agent }))
+    }
+
+    /// `POST`s the JSON `body` to `<url>/<operation>/<key_ref>`,
+    /// returns the `data.<field>` of the response
+    fn post(&self, operation: &str, key_ref: &str, body: Value, field: &str)
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
+        let url = format!("{}/{}/{}", self.url, operation, key_ref);
+        debug2!("KMS request: POST {url}");
+        let mut request = self.agent.post(&url);
+        if let Some(token) = &self.token {
+            if ! self.token_header.is_empty() {
+                request = request.set(&self.token_header, token);
+            }
+        }
+        check_for_interrupts!();
+        let response = request.send_json(body);
+        // the request may block until enigma.kms_timeout
+        check_for_interrupts!();
+        let response: Value = response
+            .map_err(|e| format!("KMS {} request failed: {}", operation, e))?
+            .into_json()?;
+        match response["data"][field].as_str() {
+            Some(value) => Ok(value.to_string()),
+            None => Err(format!("KMS {} response without data.{}",
+                operation, field).into())
+        }
+    }
+}
+
+impl KeyProvider for HttpKeyProvider {
+    fn wrap_key(&self, key_ref: &str, data_key: &[u8])
+    -> Result<String, Box<dyn std::error::Error + 'static>> {
+        let mut plaintext = encode_block(data_key);
+        let wrapped = self.post("encrypt", key_ref,
+            json!({ "plaintext": plaintext }), "ciphertext");
+        plaintext.zeroize();
+        wrapped
+    }
+
+    fn unwrap_key(&self, key_ref: &str, wrapped: &str)
+    -> Result<Vec<u8>, Box<dyn std::error::Error + 'static>> {
+        let mut plaintext = self.post("decrypt", key_ref,
+            json!({ "ciphertext": wrapped }), "plaintext")?;
+        let data_key = decode_block(&plaintext);
+        plaintext.zeroize();
+        Ok(data_key?)
+    }
+
+    fn cache_id(&self) -> String {
+        format!("{}\n{}", self.url, self.token.as_deref().unwrap_or(""))
+    }
+}
+
+/// Data keys of the current transaction, dropped (and zeroized) when it
+/// ends, so each KMS key and wrapped data key costs one KMS request per
+/// transaction
+struct DataKeys {
+    /// `KeyProvider::cache_id()` of the cached keys
+    cache_id: String,
+    /// data key and wrapped data key to encrypt, by KMS key
+    wrapping: BTreeMap<String, (Zeroizing<Vec<u8>>, String)>,
+    /// unwrapped data keys, by KMS key and wrapped data key
+    unwrapped: BTreeMap<(String, String), Zeroizing<Vec<u8>>>,
+}
+
+/// Unwrapped data keys cached per transaction. Values encrypted in other
+/// transactions have their own data keys.
+const MAX_UNWRAPPED_KEYS: usize = 1024;
+
+static DATA_KEYS: Lazy<Mutex<Option<DataKeys>>> = Lazy::new(||
+    Mutex::new(None));
+
+/// Runs `f` with the data keys cached for `cache_id` in this transaction
+fn with_data_keys<T>(cache_id: &str, f: impl FnOnce(&mut DataKeys) -> T)
+-> Result<T, Box<dyn std::error::Error + 'static>> {
+    let mut cache = DATA_KEYS.lock()
+        .map_err(|e| format!("KMS data keys: could not get lock: {}", e))?;
+    if cache.is_none() {
+        register_xact_callback(PgXactCallbackEvent::Commit, forget_data_keys);
+        register_xact_callback(PgXactCallbackEvent::Abort, forget_data_keys);
+        register_xact_callback(PgXactCallbackEvent::Prepare, 
+            forget_data_keys);
+    }
+    let keys = cache.get_or_insert_with(|| DataKeys {
+        cache_id: cache_id.to_string(),
+        wrapping: BTreeMap::new(),
+        unwrapped: BTreeMap::new()
+    });
+    if keys.cache_id != cache_id {
+        // other KMS or credentials in the same transaction
+        keys.cache_id = cache_id.to_string();
+        keys.wrapping.clear();
+        keys.unwrapped.clear();
+    }
+    Ok(f(keys))
+}
+
+fn forget_data_keys() {
+    if let Ok(mut cache) = DATA_KEYS.lock() {
+        cache.take();
+    }
+}
+
+impl dyn KeyProvider {
+    /// Data key to encrypt with the KMS key `key_ref`, and the data key
+    /// wrapped. Values encrypted in the same transaction with the same
+    /// KMS key share the data key, wrapped once.
+    pub fn data_key(&self, key_ref: &str)
+    -> Result<(Zeroizing<Vec<u8>>, String), 
+        Box<dyn std::error::Error + 'static>> {
+        let cache_id = self.cache_id();
+        if let Some(cached) = with_data_keys(&cache_id, |keys| 
+            keys.wrapping.get(key_ref).map(|(data_key, wrapped)| 
+                (data_key.clone(), wrapped.clone())))? {
+            return Ok(cached);
+        }
+        let mut data_key = Zeroizing::new(vec![0u8; AES_KEY_LEN]);
+        rand_bytes(&mut data_key)?;
+        let wrapped = self.wrap_key(key_ref, &data_key)?;
+        with_data_keys(&cache_id, |keys| keys.wrapping.insert(
+            key_ref.to_string(),
+            (data_key.clone(), wrapped.clone())))?;
+        Ok((data_key, wrapped))
+    }
+
+    /// Data key unwrapped with the KMS key `key_ref`, once per transaction
+    fn unwrap_cached(&self, key_ref: &str, wrapped: &str)
+    -> Result<Vec<u8>, Box<dyn std::error::Error + 'static>> {
+        let cache_id = self.cache_id();
+        let id = (key_ref.to_string(), wrapped.to_string());
+        if let Some(data_key) = with_data_keys(&cache_id, |keys| 
+            keys.unwrapped.get(&id).map(|k| k.to_vec()))? {
+            return Ok(data_key);
+        }
+        let data_key = Zeroizing::new(self.unwrap_key(key_ref, wrapped)?);
+        with_data_keys(&cache_id, |keys| {
+            if keys.unwrapped.len() < MAX_UNWRAPPED_KEYS {
+                keys.unwrapped.insert(id, data_key.clone());
+            }
+        })?;
+        Ok(data_key.to_vec())
+    }
+}
+
+impl Decrypt<Enigma> for dyn KeyProvider {
+    fn decrypt(&self, enigma: Enigma)
+    -> Result<Enigma, Box<dyn std::error::Error + 'static>> {
+        match enigma {
+            Enigma::KMS(_,_,msg) => Ok(Enigma::plain(kms_decrypt(msg,
+                |key_ref, wrapped| self.unwrap_cached(key_ref, wrapped))?)),
+            _ => Err("Message is not KMS encrypted.".into())
+        }
+    }
+}
+
+/// Key provider configured in the session settings.
+/// `None` if there is no key provider.
+pub fn key_provider()
+-> Result<Option<Box<dyn KeyProvider>>, Box<dyn std::error::Error + 'static>> {
+    Ok(HttpKeyProvider::from_settings()?
+        .map(|p| Box::new(p) as Box<dyn KeyProvider>))
+}
+
+/// KMS key name from a `kms:<key name>` public key.
+/// Key names are used in URLs: a letter or digit followed by letters,
+/// digits, `-`, `_` and `.` only.
+pub fn kms_key_ref(key: &str) -> Option<&str> {
+    let key_ref = key.trim().strip_prefix(KMS_KEY_PREFIX)?;
+    let mut chars = key_ref.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    if valid { Some(key_ref) } else { None }
+}
@@ -1,56 +1,250 @@
 mod common;
 mod crypt;
+mod guc;
+mod invalidate;
+mod jobs;
+mod key_file;
 mod key_map;
+mod key_provider;
+mod preload;
 mod priv_key;
 mod pub_key;
+mod redact;
+mod rotate;
+mod schema;
+mod secret;
+mod secret_key;
 mod types;
+mod vault;
 
-use crate::key_map::{PrivKeysMap,PubKeysMap};
-use crate::pub_key::insert_public_key;
+use crate::key_file::{read_key_file,read_key_file_bytes};
+use crate::key_map::{PrivKeysMap,PubKeysMap,SecretKeysMap};
+use crate::priv_key::PrivKey;
+use crate::pub_key::{
+    PubKey,delete_public_key_row,get_key_fingerprint,get_public_key,
+    insert_public_key,key_columns,key_id_from_name,update_key_info
+};
+use crate::schema::qualified;
+use crate::types::enigma::Enigma;
+use crate::types::enigma_pgp::Epgp;
 use once_cell::sync::Lazy;
+use pgrx::datum::{DatumWithOid,Interval};
 use pgrx::prelude::*;
-use std::fs;
+use std::time::Duration;
 
 
 pgrx::pg_module_magic!();
 
 static PRIV_KEYS: Lazy<PrivKeysMap> = Lazy::new(|| PrivKeysMap::new());
 static PUB_KEYS: Lazy<PubKeysMap> = Lazy::new(|| PubKeysMap::new());
+static SECRET_KEYS: Lazy<SecretKeysMap> = Lazy::new(|| SecretKeysMap::new());
 
 
-
+#[pg_guard]
+pub extern "C-unwind" fn _PG_init() {
+    guc::init();
+    invalidate::init();
+    jobs::init();
+    key_map::init();
+    redact::init();
+    vault::init();
+    preload::init();
+}
 
 /// SQL function for setting private key in memory (PrivKeysMap)
 /// All in-memory private keys will be lost when session is closed
 /// and postgres sessionprocess ends.
+/// The key is forgotten once `ttl` (default `enigma.private_key_ttl`)
+/// lapses, when it is used again or at transaction end.
+#[pg_extern(stable)]
+fn set_private_key(id: i32, key: &str, pass: &str,
+ttl: default!(Option<Interval>, "NULL"))
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    if id < 1 {
+        return Err("Key id must be a positive integer".into());
+    }
+    PRIV_KEYS.set(id as u32, key, pass, private_key_ttl(ttl)?)
+}
+
+/// Same as `set_private_key()` using the key name
+#[pg_extern(stable, name = "set_private_key", requires = [ "shell_type" ])]
+fn set_private_key_by_name(name: &str, key: &str, pass: &str,
+ttl: default!(Option<Interval>, "NULL"))
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    set_private_key(key_id_from_name(name)?, key, pass, ttl)
+}
+
+/// Same as `set_private_key()` for keys without passphrase
+#[pg_extern(stable, name = "set_private_key")]
+fn set_private_key_without_pass(id: i32, key: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    set_private_key(id, key, "", None)
+}
+
+/// Same as `set_private_key()` using the key name, without passphrase
+#[pg_extern(stable, name = "set_private_key", requires = [ "shell_type" ])]
+fn set_private_key_without_pass_by_name(name: &str, key: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    set_private_key(key_id_from_name(name)?, key, "", None)
+}
+
+/// Same as `set_private_key()`, but the key is forgotten when the current
+/// transaction commits or rolls back, like `SET LOCAL`
+#[pg_extern(stable)]
+fn set_private_key_local(id: i32, key: &str, pass: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    if id < 1 {
+        return Err("Key id must be a positive integer".into());
+    }
+    PRIV_KEYS.set_local(id as u32, key, pass)
+}
+
+/// Same as `set_private_key_local()` using the key name
+#[pg_extern(stable, name = "set_private_key_local", 
+    requires = [ "shell_type" ])]
+fn set_private_key_local_by_name(name: &str, key: &str, pass: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    set_private_key_local(key_id_from_name(name)?, key, pass)
+}
+
+/// Sets the private key with `label` in the PKCS#11 token in `slot`
+/// (`enigma.pkcs11_module`). The key never leaves the token: only the
+/// logged in token session is kept in memory.
 #[pg_extern(stable)]
-fn set_private_key(id: i32, key: &str, pass: &str)
+fn set_private_key_pkcs11(id: i32, slot: i64, label: &str, pin: &str,
+ttl: default!(Option<Interval>, "NULL"))
 -> Result<String, Box<dyn std::error::Error + 'static>> {
     if id < 1 {
         return Err("Key id must be a positive integer".into());
     }
-    PRIV_KEYS.set(id as u32, key, pass)
+    if slot < 0 {
+        return Err("PKCS#11 slot must be zero or greater".into());
+    }
+    PRIV_KEYS.set_key(id as u32, PrivKey::pkcs11(slot as u64, label, pin)?,
+        private_key_ttl(ttl)?)
+}
+
+/// Private key time to live: `ttl` or `enigma.private_key_ttl` seconds.
+/// `None` if the key does not expire.
+fn private_key_ttl(ttl: Option<Interval>)
+-> Result<Option<Duration>, Box<dyn std::error::Error + 'static>> {
+    match ttl {
+        Some(interval) => match Duration::try_from(interval) {
+            Ok(d) if ! d.is_zero() => Ok(Some(d)),
+            _ => Err("Private key TTL must be a positive interval".into())
+        },
+        None => match guc::PRIVATE_KEY_TTL.get() {
+            0 => Ok(None),
+            secs => Ok(Some(Duration::from_secs(secs as u64)))
+        }
+    }
 }
 
-// TODO: polymorphic set_private_key() without pass
 // TODO: polymorphic set_private_key() without typmod (key_id 0)
 
 
 /// SQL function for setting public key in memory (PubKeysMap)
 /// Also inserts provided public key into enigma public keys table, 
 /// making it available for other sessions.
-#[pg_extern(volatile, requires = [ "shell_type" ])]
+/// Refuses to replace an existing key with a different one, 
+/// see `replace_public_key()`.
+#[pg_extern(volatile, security_definer, requires = [ "shell_type" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
 fn set_public_key(id: i32, key: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    store_public_key(id, key, None, false)
+}
+
+/// Same as `set_public_key()`, also naming the key. The name can be used
+/// instead of the key id, like in `Enigma('payroll')`.
+#[pg_extern(volatile, security_definer, name = "set_public_key",
+    requires = [ "shell_type" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
+fn set_named_public_key(id: i32, key: &str, name: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    if name.is_empty() || name.parse::<i32>().is_ok() {
+        return Err("Key name must not be empty nor a number".into());
+    }
+    store_public_key(id, key, Some(name), false)
+}
+
+/// Sets the public key with `name`
+#[pg_extern(volatile, security_definer, name = "set_public_key",
+    requires = [ "shell_type" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
+fn set_public_key_by_name(name: &str, key: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    set_public_key(key_id_from_name(name)?, key)
+}
+
+/// Replaces the public key with `id` by a different key. The previous key
+/// is kept in the key history table and values already encrypted with it
+/// keep its fingerprint in the Key Print header.
+#[pg_extern(volatile, security_definer, requires = [ "shell_type" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
+fn replace_public_key(id: i32, key: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    store_public_key(id, key, None, true)
+}
+
+/// Same as `replace_public_key()` using the key name
+#[pg_extern(volatile, security_definer, name = "replace_public_key", 
+    requires = [ "shell_type" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
+fn replace_public_key_by_name(name: &str, key: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    replace_public_key(key_id_from_name(name)?, key)
+}
+
+/// Inserts the public key in the keys table and in memory.
+/// A key id that already has a key is only stored if `replace` is true.
+fn store_public_key(id: i32, key: &str, name: Option<&str>, replace: bool)
 -> Result<String, Box<dyn std::error::Error + 'static>> {
     if id < 0 { // TODO: Polymorphic default (without ID)
         return Err("Key id must be zero or greater".into());
     }
-    match insert_public_key(id, key)? {
-        Some(_) => PUB_KEYS.set(id as u32, key),
+    let pub_key = PubKey::new(key)?;
+    let info = pub_key.info()?;
+    match get_key_fingerprint(id)? {
+        Some(current) if current.is_empty() && ! replace => {
+            return Err(format!("Public key {} already exists. \
+                Use replace_public_key() to replace it", id).into());
+        },
+        Some(current) if ! replace => {
+            return Err(format!("Public key {} already exists with \
+                fingerprint {}. Use replace_public_key() to replace it", 
+                id, current).into());
+        },
+        Some(current) if current != info.fingerprint => {
+            warning!("Replacing public key {} fingerprint {} with {}", 
+                id, current, info.fingerprint);
+        },
+        _ => ()
+    }
+    match insert_public_key(id, key, name, &info)? {
+        Some(_) => PUB_KEYS.set_key(id as u32, pub_key),
         None => Err(format!("No key ({}) inserted", id).into())
     }
 }
 
+/// Stores the fingerprint and metadata of a public key set before 0.6.0.
+/// Called by the upgrade script, before the key history is created.
+#[pg_extern(volatile, requires = [ "shell_type" ])]
+fn _enigma_backfill_public_key(id: i32)
+-> Result<Option<String>, Box<dyn std::error::Error + 'static>> {
+    let key = match get_public_key(id)? {
+        Some(key) => key,
+        None => return Ok(None)
+    };
+    match PubKey::parse(&key) {
+        Ok(pub_key) => Ok(update_key_info(id, &pub_key.info()?)?),
+        Err(e) => {
+            warning!("Public key {} has no fingerprint: {}", id, e);
+            Ok(None)
+        }
+    }
+}
+
 // TODO: polymorphic set_public_key() without typmod (key_id 0)
 // TODO: insert_public_key() Postgres function
 
@@ -64,6 +258,36 @@ fn forget_private_key(id: i32)
     PRIV_KEYS.del(id as u32)
 }
 
+/// Same as `forget_private_key()` using the key name
+#[pg_extern(stable, name = "forget_private_key", requires = [ "shell_type" ])]
+fn forget_private_key_by_name(name: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    forget_private_key(key_id_from_name(name)?)
+}
+
+/// SQL function for setting the secret (symmetric) key of `Esym(id)`
+/// values in memory (SecretKeysMap). `key` is a 256 bit key as 64 hex
+/// digits or a passphrase, derived with the function and salt kept
+/// for the key id in _enigma_secret_keys.
+#[pg_extern(volatile, requires = [ "secret_keys" ])]
+fn set_secret_key(id: i32, key: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    if id < 1 {
+        return Err("Key id must be a positive integer".into());
+    }
+    SECRET_KEYS.set(id as u32, key)
+}
+
+/// Delete the secret key from memory (SecretKeysMap)
+#[pg_extern(stable)]
+fn forget_secret_key(id: i32)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    if id < 1 {
+        return Err("Key id must be a positive integer".into());
+    }
+    SECRET_KEYS.del(id as u32)
+}
+
 /// Delete the public key from memory (PubKeysMap)
 #[pg_extern(stable)]
 fn forget_public_key(id: i32)
@@ -74,24 +298,153 @@ fn forget_public_key(id: i32)
     PUB_KEYS.del(id as u32)
 }
 
-// TODO: delete_public_key() Postgres function
+/// Same as `forget_public_key()` using the key name
+#[pg_extern(stable, name = "forget_public_key", requires = [ "shell_type" ])]
+fn forget_public_key_by_name(name: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    forget_public_key(key_id_from_name(name)?)
+}
+
+/// Deletes the public key from the keys table and from memory.
+/// Refuses to delete a key still used in a column type modifier,
+/// unless `force` is true.
+#[pg_extern(volatile, security_definer, requires = [ "shell_type" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
+fn delete_public_key(id: i32, force: default!(bool, false))
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    if id < 0 {
+        return Err("Key id must be zero or greater".into());
+    }
+    let columns = key_columns(id)?;
+    if ! columns.is_empty() {
+        if ! force {
+            return Err(format!("Public key {} is used by column {}. \
+                Use force => true to delete it anyway", 
+                id, columns.join(", ")).into());
+        }
+        warning!("Deleting public key {} used by column {}", 
+            id, columns.join(", "));
+    }
+    match delete_public_key_row(id)? {
+        Some(_) => {
+            debug1!("{}", PUB_KEYS.del(id as u32)?);
+            Ok(format!("key {}: public key deleted", id))
+        },
+        None => Err(format!("No public key with key_id: {}", id).into())
+    }
+}
 
-/// Sets the private key reading it from a file
-#[pg_extern(stable)]
-fn set_private_key_from_file(id: i32, file_path: &str, pass: &str)
+/// Same as `delete_public_key()` using the key name
+#[pg_extern(volatile, security_definer, name = "delete_public_key",
+    requires = [ "shell_type" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
+fn delete_public_key_by_name(name: &str, force: default!(bool, false))
 -> Result<String, Box<dyn std::error::Error + 'static>> {
-    let contents = fs::read_to_string(file_path)
-    .expect("Error reading private key file");
-    set_private_key(id, &contents, pass)
+    delete_public_key(key_id_from_name(name)?, force)
 }
 
-/// Sets the public key reading it from a file
+/// Sets the private key reading it from a file.
+/// Only superusers and members of `pg_read_server_files` can read files,
+/// inside `enigma.key_directory` when it is set.
+/// Files without PEM or PGP armor are read as binary DER.
 #[pg_extern(stable)]
+fn set_private_key_from_file(id: i32, file_path: &str, pass: &str,
+ttl: default!(Option<Interval>, "NULL"))
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    if id < 1 {
+        return Err("Key id must be a positive integer".into());
+    }
+    let contents = read_key_file_bytes(file_path)?;
+    match std::str::from_utf8(&contents) {
+        Ok(armored) if armored.contains("-----BEGIN ") => 
+            set_private_key(id, armored, pass, ttl),
+        _ => PRIV_KEYS.set_key(id as u32, PrivKey::from_der(&contents, pass)?,
+            private_key_ttl(ttl)?)
+    }
+}
+
+/// Same as `set_private_key_from_file()` using the key name
+#[pg_extern(stable, name = "set_private_key_from_file",
+    requires = [ "shell_type" ])]
+fn set_private_key_from_file_by_name(name: &str, file_path: &str, pass: &str,
+ttl: default!(Option<Interval>, "NULL"))
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    set_private_key_from_file(key_id_from_name(name)?, file_path, pass, ttl)
+}
+
+/// Sets the public key reading it from a file.
+/// Same file restrictions as `set_private_key_from_file()`.
+/// The file is read with the caller's privileges, then the key is stored
+/// by `set_public_key()`.
+#[pg_extern(volatile, requires = [ "shell_type" ])]
 fn set_public_key_from_file(id: i32, file_path: &str)
 -> Result<String, Box<dyn std::error::Error + 'static>> {
-    let contents = fs::read_to_string(file_path)
-        .expect("Error reading public file");
-    set_public_key(id, &contents)
+    let contents = read_key_file(file_path)?;
+    call_set_public_key(id, &contents, None)
+}
+
+/// Sets the public key reading it from a file, also naming the key
+#[pg_extern(volatile, name = "set_public_key_from_file",
+    requires = [ "shell_type" ])]
+fn set_named_public_key_from_file(id: i32, file_path: &str, name: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    let contents = read_key_file(file_path)?;
+    call_set_public_key(id, &contents, Some(name))
+}
+
+/// Calls the SECURITY DEFINER `set_public_key()`, the only way to write
+/// the keys table without being its owner
+fn call_set_public_key(id: i32, key: &str, name: Option<&str>)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    let function = qualified("set_public_key")?;
+    let set = match name {
+        Some(name) => {
+            let args = unsafe {
+                [
+                    DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()),
+                    DatumWithOid::new(key, PgBuiltInOids::TEXTOID.value()),
+                    DatumWithOid::new(name, PgBuiltInOids::TEXTOID.value()),
+                ]
+            };
+            Spi::get_one_with_args::<String>(
+                &format!("SELECT {function}($1, $2, $3)"), &args)?
+        },
+        None => {
+            let args = unsafe {
+                [
+                    DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()),
+                    DatumWithOid::new(key, PgBuiltInOids::TEXTOID.value()),
+                ]
+            };
+            Spi::get_one_with_args::<String>(
+                &format!("SELECT {function}($1, $2)"), &args)?
+        }
+    };
+    set.ok_or("set_public_key() returned NULL".into())
+}
+
+/// Same as `set_public_key_from_file()` using the key name
+#[pg_extern(volatile, name = "set_public_key_from_file",
+    requires = [ "shell_type" ])]
+fn set_public_key_from_file_by_name(name: &str, file_path: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    set_public_key_from_file(key_id_from_name(name)?, file_path)
+}
+
+/// Encrypts the value to several PGP keys at once (e.g. an operational
+/// key plus an escrow key). First key id goes in the Enigma header, the
+/// rest in the More Keys header. Any of the private keys can decrypt it.
+#[pg_extern(volatile, requires = [ "enigma_type" ])]
+fn enigma_encrypt(value: &str, key_ids: Vec<i32>)
+-> Result<Enigma, Box<dyn std::error::Error + 'static>> {
+    Enigma::try_from(value)?.encrypt_multi(&key_ids)
+}
+
+/// Same as `enigma_encrypt()` for `Epgp` values
+#[pg_extern(volatile, requires = [ "epgp_type" ])]
+fn epgp_encrypt(value: &str, key_ids: Vec<i32>)
+-> Result<Epgp, Box<dyn std::error::Error + 'static>> {
+    Epgp::try_from(value)?.encrypt_multi(&key_ids)
 }
 
 
@@ -111,15 +464,23 @@ extension_sql_file!("../sql/shell_type.sql", bootstrap);
 // Create the real type
 extension_sql_file!("../sql/enigma_type.sql", creates = [Type(Enigma)],
     requires = ["shell_type", enigma_input, enigma_output, 
-    enigma_receive, enigma_send, enigma_typmod_in],
+    enigma_receive, enigma_send, enigma_typmod_in, enigma_typmod_out],
 );
 extension_sql_file!("../sql/epgp_type.sql", creates = [Type(Epgp)],
     requires = ["shell_type", epgp_input, epgp_output, 
-    epgp_receive, epgp_send, epgp_typmod_in],
+    epgp_receive, epgp_send, epgp_typmod_in, epgp_typmod_out],
 );
 extension_sql_file!("../sql/ersa_type.sql", creates = [Type(Ersa)],
     requires = ["shell_type", ersa_input, ersa_output, 
-    ersa_receive, ersa_send, ersa_typmod_in],
+    ersa_receive, ersa_send, ersa_typmod_in, ersa_typmod_out],
+);
+extension_sql_file!("../sql/eecc_type.sql", creates = [Type(Eecc)],
+    requires = ["shell_type", eecc_input, eecc_output, 
+    eecc_receive, eecc_send, eecc_typmod_in, eecc_typmod_out],
+);
+extension_sql_file!("../sql/esym_type.sql", creates = [Type(Esym)],
+    requires = ["shell_type", esym_input, esym_output, 
+    esym_receive, esym_send, esym_typmod_in, esym_typmod_out],
 );
 
 // Creates the casting function so we can get the key id in the
@@ -136,6 +497,29 @@ extension_sql_file!("../sql/epgp_casts.sql",
 extension_sql_file!("../sql/ersa_casts.sql",
     requires = ["ersa_type", ersa_as_ersa, string_as_ersa]
 );
+extension_sql_file!("../sql/eecc_casts.sql",
+    requires = ["eecc_type", eecc_as_eecc, string_as_eecc]
+);
+extension_sql_file!("../sql/esym_casts.sql",
+    requires = ["esym_type", esym_as_esym, string_as_esym]
+);
+
+// Re-encryption jobs queue for the background worker
+extension_sql_file!("../sql/enigma_jobs.sql");
+
+// Roles granted each vault private key
+extension_sql_file!("../sql/vault.sql");
+
+// Key derivation function and salt of each secret key passphrase
+extension_sql_file!("../sql/secret_keys.sql");
+
+// Backends drop their cached public keys when the keys table changes
+extension_sql_file!("../sql/public_keys_trigger.sql",
+    requires = ["shell_type", _enigma_public_keys_changed]
+);
+
+// Roles and privileges, after all the functions are created
+extension_sql_file!("../sql/privileges.sql", finalize);
 
 
 /**************************************************************************
@@ -394,6 +778,1135 @@ SELECT CAST(b AS Text) FROM testab LIMIT 1;
         Err("Should return decrypted string".into()) 
     } 
 
+    /// Encrypt the same plain text twice with the same PGP key.
+    /// Each message must get its own session key (and ciphertext).
+    #[pg_test]
+    fn e14_pgp_distinct_session_keys()  -> Result<(), Box<dyn Error>> {
+        use crate::crypt::pgp::{
+            pgp_encrypt_with_session_key, pgp_pub_key_from
+        };
+        use crate::pub_key::expired_before;
+        let armored = std::fs::read_to_string(
+            "../../../test/public-key.asc")?;
+        let pub_key = pgp_pub_key_from(&armored)?;
+        let (msg1, sk1) = pgp_encrypt_with_session_key(&pub_key,
+            expired_before(), String::from("my PGP test record"))?;
+        let (msg2, sk2) = pgp_encrypt_with_session_key(&pub_key,
+            expired_before(), String::from("my PGP test record"))?;
+        if sk1 != sk2 && msg1 != msg2 { return Ok(()); }
+        Err("Should return distinct session keys".into())
+    }
+
+    /// Insert a record longer than a single RSA block, then set private 
+    /// key and query the decrypted value.
+    /// Long records are encrypted with an RSA-wrapped AES-256-GCM key.
+    #[pg_test]
+    fn e15_long_rsa_record()  -> Result<(), Box<dyn Error>> {
+        let long_record = "my long RSA test record ".repeat(20);
+        Spi::run(&format!(
+        "
+CREATE TABLE testab ( a SERIAL, b Ersa(3));
+SELECT set_public_key_from_file(3, '../../../test/alice_public.pem'); 
+INSERT INTO testab (b) VALUES ('{long_record}'::Text);
+SELECT set_private_key_from_file(3, 
+    '../../../test/alice_private.pem', 'Prueba123!'); 
+        "))? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT CAST(b AS Text) FROM testab LIMIT 1;
+        ")? {
+            info!("Decrypted value: {}", res);
+            if res == long_record { return Ok(()); }
+        } 
+        Err("Should return decrypted long string".into()) 
+    } 
+
+    /// New RSA values use OAEP padding, recorded in the `PgE_RSA2` 
+    /// header scheme line. `enigma.rsa_padding` selects the digest.
+    #[pg_test]
+    fn e16_rsa_oaep_padding()  -> Result<(), Box<dyn Error>> {
+        use crate::types::enigma_rsa::Ersa;
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Ersa(3));
+SELECT set_public_key_from_file(3, '../../../test/alice_public.pem'); 
+INSERT INTO testab (b) VALUES ('my OAEP SHA-256 test record'::Text);
+SET enigma.rsa_padding = 'oaep-sha1';
+INSERT INTO testab (b) VALUES ('my OAEP SHA-1 test record'::Text);
+        ")? ; 
+        let sha256 = Spi::get_one::<Ersa>("
+SELECT b FROM testab WHERE a = 1;
+        ")?.ok_or("No OAEP SHA-256 record")?.to_string();
+        let sha1 = Spi::get_one::<Ersa>("
+SELECT b FROM testab WHERE a = 2;
+        ")?.ok_or("No OAEP SHA-1 record")?.to_string();
+        if ! sha256.starts_with("PgE_RSA200000003\nOAEP-SHA256\n") 
+        || ! sha1.starts_with("PgE_RSA200000003\nOAEP-SHA1\n") {
+            return Err("Should return PgE_RSA2 header with scheme".into());
+        }
+        Spi::run(
+        "
+SELECT set_private_key_from_file(3, 
+    '../../../test/alice_private.pem', 'Prueba123!'); 
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT string_agg(CAST(b AS Text), ',' ORDER BY a) FROM testab;
+        ")? {
+            info!("Decrypted values: {}", res);
+            if res.as_str() == 
+            "my OAEP SHA-256 test record,my OAEP SHA-1 test record" {
+                return Ok(());
+            }
+        } 
+        Err("Should return decrypted strings".into()) 
+    } 
+
+    /// Insert a record into an Eecc column using a P-256 key, then set
+    /// private key and query the decrypted value.
+    #[pg_test]
+    fn e17_eecc_p256()  -> Result<(), Box<dyn Error>> {
+        use crate::types::enigma_ecc::Eecc;
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Eecc(4));
+SELECT set_public_key_from_file(4, '../../../test/bob_ec_public.pem'); 
+INSERT INTO testab (b) VALUES ('my ECC test record'::Text);
+        ")? ; 
+        let encrypted = Spi::get_one::<Eecc>("
+SELECT b FROM testab LIMIT 1;
+        ")?.ok_or("No ECC record")?.to_string();
+        if ! encrypted.starts_with("PgE_ECC100000004\n") {
+            return Err("Should return PgE_ECC1 header".into());
+        }
+        Spi::run(
+        "
+SELECT set_private_key_from_file(4, 
+    '../../../test/bob_ec_private.pem', 'Prueba123!'); 
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT CAST(b AS Text) FROM testab LIMIT 1;
+        ")? {
+            info!("Decrypted value: {}", res);
+            if res.as_str() == "my ECC test record" { return Ok(()); }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Enigma column encrypts with an X25519 key
+    #[pg_test]
+    fn e18_enigma_x25519()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(5));
+SELECT set_public_key_from_file(5, 
+    '../../../test/carol_x25519_public.pem'); 
+INSERT INTO testab (b) VALUES ('my X25519 test record'::Text);
+SELECT set_private_key_from_file(5, 
+    '../../../test/carol_x25519_private.pem', 'Prueba123!'); 
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT CAST(b AS Text) FROM testab LIMIT 1;
+        ")? {
+            info!("Decrypted value: {}", res);
+            if res.as_str() == "my X25519 test record" { return Ok(()); }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Encrypt to the operational key and an escrow key, then decrypt
+    /// with only the escrow private key set.
+    #[pg_test]
+    fn e19_escrow_more_keys()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+SELECT set_public_key_from_file(6, 
+    '../../../test/escrow-public-key.asc'); 
+INSERT INTO testab (b) 
+    VALUES (enigma_encrypt('my escrow test record', ARRAY[2,6]));
+        ")? ; 
+        let encrypted = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")?.ok_or("No escrow record")?.to_string();
+        if ! encrypted.starts_with(
+        "PgE_PGP100000002MOREKEYS000000000000000000000006\n") {
+            return Err("Should return More Keys header".into());
+        }
+        Spi::run(
+        "
+SELECT set_private_key_from_file(6, 
+    '../../../test/escrow-private-key.asc', 'Prueba123!'); 
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT CAST(b AS Text) FROM testab LIMIT 1;
+        ")? {
+            info!("Decrypted value: {}", res);
+            if res.as_str() == "my escrow test record" { return Ok(()); }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Re-encrypt a PGP value with the RSA key
+    #[pg_test]
+    fn e20_reencrypt_value()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+SELECT set_public_key_from_file(3, '../../../test/alice_public.pem'); 
+INSERT INTO testab (b) VALUES ('my reencrypt test record'::Text);
+SELECT set_private_key_from_file(2, 
+    '../../../test/private-key.asc', 'Prueba123!'); 
+        ")? ; 
+        if let Some(res) = Spi::get_one::<i32>("
+SELECT enigma_key_id(enigma_reencrypt(b, 3)) FROM testab LIMIT 1;
+        ")? {
+            if res == 3 { return Ok(()); }
+        } 
+        Err("Should return new key id 3".into()) 
+    } 
+
+    /// Rotate a PGP column to the RSA key in batches of 2 rows
+    #[pg_test]
+    fn e21_rotate_column()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+SELECT set_public_key_from_file(3, '../../../test/alice_public.pem'); 
+INSERT INTO testab (b) VALUES ('rotated 1'::Text);
+INSERT INTO testab (b) VALUES ('rotated 2'::Text);
+INSERT INTO testab (b) VALUES ('rotated 3'::Text);
+SELECT set_private_key_from_file(2, 
+    '../../../test/private-key.asc', 'Prueba123!'); 
+        ")? ; 
+        let batches = Spi::get_one::<String>("
+SELECT string_agg(format('%s:%s:%s', batch, reencrypted, remaining), ',')
+FROM enigma_rotate_column('testab', 'b', 3, 2);
+        ")?.ok_or("No batches")?;
+        if batches.as_str() != "1:2:1,2:1:0" {
+            return Err(format!("Wrong batches: {batches}").into());
+        }
+        Spi::run(
+        "
+SELECT forget_private_key(2);
+SELECT set_private_key_from_file(3, 
+    '../../../test/alice_private.pem', 'Prueba123!'); 
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT string_agg(CAST(b AS Text), ',' ORDER BY a) FROM testab
+WHERE enigma_key_id(b) = 3;
+        ")? {
+            info!("Decrypted values: {}", res);
+            if res.as_str() == "rotated 1,rotated 2,rotated 3" {
+                return Ok(());
+            }
+        } 
+        Err("Should return rotated values".into()) 
+    } 
+
+    /// Queue a rotation job and run its batches as the worker does.
+    /// Values encrypted with a third key are left as they are.
+    #[pg_test]
+    fn e22_rotation_job()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+SELECT set_public_key_from_file(3, '../../../test/alice_public.pem'); 
+INSERT INTO testab (b) VALUES ('job 1'::Text);
+INSERT INTO testab (b) VALUES ('job 2'::Text);
+INSERT INTO testab (b) VALUES ('job 3'::Text);
+SELECT set_public_key_from_file(4, '../../../test/bob_ec_public.pem'); 
+INSERT INTO testab (b) VALUES (CAST('other key' AS Enigma(4)));
+SELECT enigma_queue_rotation('testab', 'b', 2, 3, 
+    '../../../test/private-key.asc', '../../../test/private-key.pass', 2, 0);
+        ")? ; 
+        let mut batches = 0;
+        while crate::jobs::run_next_batch()?.is_some() {
+            batches += 1;
+            if batches > 10 { return Err("Job should be done".into()); }
+        }
+        let status = Spi::get_one::<String>("
+SELECT format('%s:%s:%s', status, reencrypted, progress) FROM enigma_jobs;
+        ")?.ok_or("No job")?;
+        if status.as_str() != "done:3:100.0" {
+            return Err(format!("Wrong job status: {status}").into());
+        }
+        if let Some(res) = Spi::get_one::<String>("
+SELECT string_agg(enigma_key_id(b)::text, ',' ORDER BY a) FROM testab;
+        ")? {
+            if res.as_str() == "3,3,3,4" { return Ok(()); }
+        } 
+        Err("Should return 3 rotated values and key 4 value".into()) 
+    } 
+
+    /// Runs `sql` with psql in a new session of the test database.
+    /// Returns what psql printed, messages included, or an error if psql
+    /// failed.
+    fn psql(sql: &str) -> Result<String, Box<dyn Error>> {
+        use std::io::Write;
+        use std::process::{Command,Stdio};
+        let bindir = Spi::get_one::<String>(
+            "SELECT setting FROM pg_config WHERE name = 'BINDIR'")?
+            .ok_or("No BINDIR in pg_config")?;
+        let (host, port, user) = Spi::get_three::<String, String, String>(
+            "SELECT coalesce(host(inet_server_addr()), split_part(
+                current_setting('unix_socket_directories'), ',', 1)),
+             current_setting('port'), session_user::text")?;
+        let database = Spi::get_one::<String>("SELECT current_database()")?
+            .ok_or("No database")?;
+        let mut child = Command::new(format!("{bindir}/psql"))
+            .args(["-X", "-q", "-t", "-A", "-v", "ON_ERROR_STOP=1"])
+            .args(["-h", &host.ok_or("No host")?])
+            .args(["-p", &port.ok_or("No port")?])
+            .args(["-U", &user.ok_or("No user")?])
+            .args(["-d", &database])
+            .stdin(Stdio::piped()).stdout(Stdio::piped())
+            .stderr(Stdio::piped()).spawn()?;
+        child.stdin.take().ok_or("No psql stdin")?
+            .write_all(sql.as_bytes())?;
+        let out = child.wait_with_output()?;
+        let printed = format!("{}{}", String::from_utf8_lossy(&out.stdout),
+            String::from_utf8_lossy(&out.stderr));
+        info!("psql: {}", printed);
+        if ! out.status.success() {
+            return Err(format!("psql failed: {printed}").into());
+        }
+        Ok(printed)
+    }
+
+    /// Passphrases must not reach the server log nor pg_stat_activity.
+    /// pg_enigma is preloaded (see `postgresql_conf_options()`), so the
+    /// first statement of the psql session is redacted too.
+    #[pg_test]
+    fn e23_redact_passphrase_from_log()  -> Result<(), Box<dyn Error>> {
+        let printed = psql("
+SET client_min_messages = log;
+SET log_statement = 'all';
+SELECT set_private_key_from_file(2, 
+    '../../../test/private-key.asc', 'Prueba123!'),
+    (SELECT query FROM pg_stat_activity WHERE pid = pg_backend_pid());
+        ")?;
+        if printed.contains("Prueba123!") {
+            return Err("Passphrase should be redacted".into());
+        }
+        if ! printed.contains("statement: SELECT set_private_key_from_file(2,")
+        {
+            return Err("Should log the statement".into());
+        }
+        if ! printed.contains("|SELECT set_private_key_from_file(2,") {
+            return Err("pg_stat_activity should show the query".into());
+        }
+        if printed.matches("'********', '********')").count() == 2 {
+            return Ok(());
+        }
+        Err("Log and pg_stat_activity should show redacted query".into())
+    }
+
+    /// Column type shows the key id type modifier
+    #[pg_test]
+    fn e24_typmod_out()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2), c Ersa(3));
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT string_agg(format_type(atttypid, atttypmod), ',' ORDER BY attnum)
+FROM pg_attribute WHERE attrelid = 'testab'::regclass AND attname IN ('b','c');
+        ")? {
+            info!("Column types: {}", res);
+            if res.as_str() == "enigma(2),ersa(3)" { return Ok(()); }
+        } 
+        Err("Should return enigma(2),ersa(3)".into()) 
+    } 
+
+    /// Named key in the type modifier
+    #[pg_test]
+    fn e25_named_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc', 'payroll'); 
+CREATE TABLE testab ( a SERIAL, b Enigma('payroll'));
+INSERT INTO testab (b) VALUES ('my named key test record'::Text);
+SELECT set_private_key_from_file('payroll', 
+    '../../../test/private-key.asc', 'Prueba123!'); 
+        ")? ; 
+        let column = Spi::get_one::<String>("
+SELECT format_type(atttypid, atttypmod) FROM pg_attribute 
+WHERE attrelid = 'testab'::regclass AND attname = 'b';
+        ")?.ok_or("No column b")?;
+        if column.as_str() != "enigma(2)" {
+            return Err(format!("Wrong column type: {column}").into());
+        }
+        if let Some(res) = Spi::get_one::<String>("
+SELECT CAST(b AS Text) FROM testab WHERE enigma_key_id(b) = 2 LIMIT 1;
+        ")? {
+            info!("Decrypted value: {}", res);
+            if res.as_str() == "my named key test record" { return Ok(()); }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Key metadata in the enigma_keys view
+    #[pg_test]
+    fn e26_key_metadata()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+SELECT set_public_key_from_file(3, '../../../test/alice_public.pem'); 
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT string_agg(format('%s:%s:%s:%s', id, fingerprint, algorithm, 
+    created_at IS NOT NULL), ',' ORDER BY id) FROM enigma_keys;
+        ")? {
+            info!("Keys: {}", res);
+            if res.as_str() == "2:1801984A6FFFBF4FBE865BA2CB7D5DA21AF8B860:\
+PGP RSA 1024:t,3:1EF53DAA74CDD19BD13AE4F3FA27057CF2834265F6D87F4BDD6B6B3\
+34FB4EEC5:RSA 1024:f" { return Ok(()); }
+        } 
+        Err("Should return key metadata".into()) 
+    } 
+
+    /// Old version of test PGP key 2 expired on 2024-06-05
+    #[pg_test(error = "PGP public key cb7d5da21af8b860 expired on \
+2024-06-05 12:13:26 UTC")]
+    fn e27_refuse_expired_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+SELECT set_public_key_from_file(2, '../../../test/expired-public-key.asc'); 
+        ")? ; 
+        Err("Should refuse expired key".into()) 
+    } 
+
+    /// Key 2 can not be deleted while a column uses it
+    #[pg_test(error = "Public key 2 is used by column testab.b. \
+Use force => true to delete it anyway")]
+    fn e28_delete_used_public_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+SELECT delete_public_key(2);
+        ")? ; 
+        Err("Should refuse to delete public key 2".into()) 
+    } 
+
+    /// Forced delete removes the key from the table and from memory
+    #[pg_test]
+    fn e29_force_delete_public_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+SELECT delete_public_key(2, force => true);
+        ")? ; 
+        if let Some(res) = Spi::get_one::<i64>("
+SELECT count(*) FROM _enigma_public_keys WHERE id = 2;
+        ")? {
+            if res == 0 { return Ok(()); }
+        } 
+        Err("Should return count: 0".into()) 
+    } 
+
+    /// Key 2 can not be swapped by set_public_key()
+    #[pg_test(error = "Public key 2 already exists with fingerprint \
+1801984A6FFFBF4FBE865BA2CB7D5DA21AF8B860. Use replace_public_key() to \
+replace it")]
+    fn e30_refuse_public_key_replacement()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+SELECT set_public_key_from_file(2, '../../../test/alice_public.pem'); 
+        ")? ; 
+        Err("Should refuse to replace public key 2".into()) 
+    } 
+
+    /// Values keep the fingerprint of the key version they were 
+    /// encrypted with
+    #[pg_test]
+    fn e31_replace_public_key_history()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+INSERT INTO testab (b) VALUES ('my first secret');
+SELECT replace_public_key(2, pg_read_file('../../../test/alice_public.pem'));
+INSERT INTO testab (b) VALUES ('my second secret');
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT string_agg(format('%s:%s', h.version, h.key_print), ',' ORDER BY a) 
+FROM testab JOIN enigma_key_history h 
+ON h.id = 2 AND h.key_print = enigma_key_fingerprint(b);
+        ")? {
+            info!("Key versions: {}", res);
+            if res.as_str() == "1:1801984A6FFFBF4F,2:1EF53DAA74CDD19B" { 
+                return Ok(()); 
+            }
+        } 
+        Err("Should return key versions 1 and 2".into()) 
+    } 
+
+    /// enigma_reader can not change public keys
+    #[pg_test(error = "permission denied for function set_public_key")]
+    fn e32_reader_can_not_set_public_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE ROLE enigma_test_reader IN ROLE enigma_reader;
+SET ROLE enigma_test_reader;
+SELECT set_public_key(2, 'not a key'); 
+        ")? ; 
+        Err("Should refuse to execute set_public_key()".into()) 
+    } 
+
+    /// enigma_admin needs pg_read_server_files to read key files
+    #[pg_test(error = "Only superusers and members of pg_read_server_files \
+can read key files")]
+    fn e33_admin_can_not_read_key_file()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE ROLE enigma_test_admin IN ROLE enigma_admin;
+SET ROLE enigma_test_admin;
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+        ")? ; 
+        Err("Should refuse to read key file".into()) 
+    } 
+
+    /// Tests run with enigma.key_directory set to the test directory
+    #[pg_test(error = "Key file ../../../Cargo.toml is not in \
+enigma.key_directory")]
+    fn e34_key_file_outside_key_directory()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+SELECT set_public_key_from_file(2, '../../../Cargo.toml'); 
+        ")? ; 
+        Err("Should refuse to read file outside key directory".into()) 
+    } 
+
+    /// Cached key 135 is reloaded after another session replaces it
+    #[pg_test]
+    fn e35_reload_changed_public_key()  -> Result<(), Box<dyn Error>> {
+        let cleanup = "
+DELETE FROM _enigma_public_keys WHERE id = 135;
+DELETE FROM _enigma_public_key_history WHERE id = 135;
+        ";
+        psql(&format!("{cleanup}
+SELECT set_public_key_from_file(135, '../../../test/public-key.asc');
+        "))?;
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(135));
+INSERT INTO testab (b) VALUES ('my first secret');
+        ")? ; 
+        psql("
+SELECT replace_public_key(135, pg_read_file('../../../test/alice_public.pem'));
+        ")?;
+        let inserted = Spi::run("
+INSERT INTO testab (b) VALUES ('my second secret');
+        ");
+        psql(cleanup)?;
+        inserted?;
+        if let Some(res) = Spi::get_one::<String>("
+SELECT string_agg(enigma_key_fingerprint(b), ',' ORDER BY a) FROM testab;
+        ")? {
+            info!("Key prints: {}", res);
+            if res.as_str() == "1801984A6FFFBF4F,1EF53DAA74CDD19B" { 
+                return Ok(()); 
+            }
+        } 
+        Err("Should encrypt with the key replaced by the other session".into()) 
+    } 
+
+    /// Private key is forgotten once its TTL lapses
+    #[pg_test]
+    fn e36_private_key_ttl()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+INSERT INTO testab (b) VALUES ('my PGP test record'::Text);
+SELECT set_private_key_from_file(2, 
+    '../../../test/private-key.asc', 'Prueba123!', ttl => '1 second'); 
+SELECT pg_sleep(1.5);
+        ")? ; 
+        if let Some(res) = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")? {
+            if res.is_pgp() { return Ok(()); }
+        } 
+        Err("Should return encrypted value".into()) 
+    } 
+
+    /// Transaction private key decrypts until the transaction ends, and is
+    /// forgotten after COMMIT or ROLLBACK
+    #[pg_test]
+    fn e37_set_private_key_local()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/public-key.asc'); 
+INSERT INTO testab (b) VALUES ('my PGP test record'::Text);
+SELECT set_private_key_local(2, 
+    pg_read_file('../../../test/private-key.asc'), 'Prueba123!'); 
+        ")? ; 
+        let res = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")?.ok_or("Should return a value")?;
+        if res.value() != String::from("my PGP test record") {
+            return Err("Should return decrypted string".into());
+        }
+        let printed = psql("
+BEGIN;
+SELECT set_private_key_local(37, 
+    pg_read_file('../../../test/private-key.asc'), 'Prueba123!'); 
+COMMIT;
+SELECT forget_private_key(37);
+BEGIN;
+SELECT set_private_key_local(37, 
+    pg_read_file('../../../test/private-key.asc'), 'Prueba123!'); 
+ROLLBACK;
+SELECT forget_private_key(37);
+        ")?;
+        if printed.matches("key 37: not set").count() == 2 {
+            return Ok(());
+        }
+        Err("Key should be forgotten after COMMIT and ROLLBACK".into()) 
+    } 
+
+    /// Unencrypted PKCS#1 RSA key set without passphrase
+    #[pg_test]
+    fn e38_set_private_key_without_pass()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(3));
+SELECT set_public_key_from_file(3, '../../../test/alice_public.pem'); 
+INSERT INTO testab (b) VALUES ('my RSA test record'::Text);
+SELECT set_private_key(3, 
+    pg_read_file('../../../test/alice_private_pkcs1.pem')); 
+        ")? ; 
+        if let Some(res) = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")? {
+            if res.value() == String::from("my RSA test record") {
+                return Ok(());
+            }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Unencrypted PKCS#8 DER key file
+    #[pg_test]
+    fn e39_set_private_key_from_der_file()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(3));
+SELECT set_public_key_from_file(3, '../../../test/alice_public.pem'); 
+INSERT INTO testab (b) VALUES ('my RSA test record'::Text);
+SELECT set_private_key_from_file(3, '../../../test/alice_private.der', ''); 
+        ")? ; 
+        if let Some(res) = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")? {
+            if res.value() == String::from("my RSA test record") {
+                return Ok(());
+            }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Errors name the detected key format
+    #[pg_test(error = "Encrypted PKCS#8 PEM private key requires a \
+passphrase")]
+    fn e40_encrypted_private_key_without_pass()  
+    -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+SELECT set_private_key(3, 
+    pg_read_file('../../../test/alice_private.pem')); 
+        ")? ; 
+        Err("Should require a passphrase".into()) 
+    } 
+
+    /// Roles granted a vault private key decrypt without setting it, other
+    /// roles are refused
+    #[pg_test]
+    fn e41_vault_private_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(41));
+SELECT set_public_key_from_file(41, '../../../test/alice_public.pem'); 
+INSERT INTO testab (b) VALUES ('my RSA test record'::Text);
+SELECT set_vault_private_key(41, 
+    pg_read_file('../../../test/alice_private.pem'), 'Prueba123!'); 
+CREATE ROLE enigma_test_vault;
+SELECT grant_vault_private_key(41, 'enigma_test_vault');
+GRANT SELECT ON testab TO enigma_test_vault;
+SET ROLE enigma_test_vault;
+        ")? ; 
+        let res = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")?;
+        // role without the grant, shadowing the grants table
+        Spi::run("
+RESET ROLE;
+CREATE ROLE enigma_test_no_vault;
+GRANT SELECT ON testab TO enigma_test_no_vault;
+SET ROLE enigma_test_no_vault;
+CREATE TEMP TABLE _enigma_vault_grants(id INT, role NAME);
+INSERT INTO _enigma_vault_grants VALUES (41, current_user);
+        ")?;
+        let refused = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")?;
+        Spi::run("
+RESET ROLE;
+SELECT forget_vault_private_key(41);
+        ")?;
+        if ! refused.is_some_and(|r| r.is_rsa()) {
+            return Err("Should refuse the role without the grant".into());
+        }
+        if let Some(res) = res {
+            if res.value() == String::from("my RSA test record") {
+                return Ok(());
+            }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Private key 7 is preloaded from test/7.pem
+    #[pg_test]
+    fn e42_preloaded_private_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Enigma(7));
+SELECT set_public_key_from_file(7, '../../../test/alice_public.pem'); 
+INSERT INTO testab (b) VALUES ('my RSA test record'::Text);
+        ")? ; 
+        if let Some(res) = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")? {
+            if res.value() == String::from("my RSA test record") {
+                return Ok(());
+            }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Needs a SoftHSMv2 token, see test/softhsm-setup.sh
+    #[cfg(feature = "pkcs11")]
+    #[pg_test]
+    fn e43_pkcs11_private_key()  -> Result<(), Box<dyn Error>> {
+        let module = std::env::var("ENIGMA_PKCS11_MODULE")?;
+        let slot = std::env::var("ENIGMA_PKCS11_SLOT")?;
+        let public_key = std::fs::read_to_string(
+            std::env::var("ENIGMA_PKCS11_PUBLIC_KEY")?)?;
+        Spi::run(&format!(
+        "
+SET enigma.pkcs11_module = '{module}';
+CREATE TABLE testab ( a SERIAL, b Enigma(3));
+SELECT set_public_key(3, '{public_key}'); 
+INSERT INTO testab (b) VALUES ('my RSA test record'::Text);
+SELECT set_private_key_pkcs11(3, {slot}, 'alice', '1234'); 
+        "))? ; 
+        if let Some(res) = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")? {
+            if res.value() == String::from("my RSA test record") {
+                return Ok(());
+            }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+
+    /// Data key wrapped by a local stub of the Vault Transit API.
+    /// No private key is set.
+    #[pg_test]
+    fn e44_kms_envelope()  -> Result<(), Box<dyn Error>> {
+        let port = kms_stub("s.enigma-test")?;
+        Spi::run(&format!(
+        "
+SET enigma.kms_url = 'http://127.0.0.1:{port}/v1/transit';
+SET enigma.kms_token = 's.enigma-test';
+CREATE TABLE testab ( a SERIAL, b Enigma(44));
+SELECT set_public_key(44, 'kms:enigma-test'); 
+INSERT INTO testab (b) VALUES ('my KMS test record'::Text);
+RESET enigma.kms_url;
+        "))? ; 
+        let encrypted = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")?.ok_or("No KMS record")?.to_string();
+        if ! encrypted.starts_with("PgE_KMS10000002C") 
+        || ! encrypted.contains("\nenigma-test\nstub:v1:") {
+            return Err("Should return PgE_KMS1 header and wrapped key".into());
+        }
+        Spi::run(&format!(
+        "
+SET enigma.kms_url = 'http://127.0.0.1:{port}/v1/transit';
+        "))? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT CAST(b AS Text) FROM testab LIMIT 1;
+        ")? {
+            if res.as_str() == "my KMS test record" { return Ok(()); }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Secret key from a passphrase: the KDF salt is kept in
+    /// _enigma_secret_keys, so the same passphrase decrypts again
+    #[pg_test]
+    fn e45_esym_passphrase()  -> Result<(), Box<dyn Error>> {
+        use crate::types::enigma_sym::Esym;
+        Spi::run(
+        "
+CREATE TABLE testab ( a SERIAL, b Esym(45));
+SELECT set_secret_key(45, 'my passphrase'); 
+INSERT INTO testab (b) VALUES ('my SYM test record'::Text);
+SELECT forget_secret_key(45); 
+        ")? ; 
+        let encrypted = Spi::get_one::<Esym>("
+SELECT b FROM testab LIMIT 1;
+        ")?.ok_or("No SYM record")?.to_string();
+        if ! encrypted.starts_with("PgE_SYM10000002D") {
+            return Err("Should return PgE_SYM1 header".into());
+        }
+        Spi::run("SELECT set_secret_key(45, 'my passphrase');")?;
+        if let Some(res) = Spi::get_one::<String>("
+SELECT CAST(b AS Text) FROM testab LIMIT 1;
+        ")? {
+            if res.as_str() == "my SYM test record" { return Ok(()); }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Raw 256 bit secret key with XChaCha20-Poly1305
+    #[pg_test]
+    fn e46_esym_xchacha_raw_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+SET enigma.secret_key_cipher = 'xchacha20-poly1305';
+CREATE TABLE testab ( a SERIAL, b Esym(46));
+SELECT set_secret_key(46, 
+    '000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f'); 
+INSERT INTO testab (b) VALUES ('my XChaCha test record'::Text);
+RESET enigma.secret_key_cipher;
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT CAST(b AS Text) FROM testab LIMIT 1;
+        ")? {
+            if res.as_str() == "my XChaCha test record" { return Ok(()); }
+        } 
+        Err("Should return decrypted string".into()) 
+    } 
+
+    /// Restoring a dump copies the key history, then the keys, with an
+    /// empty search_path. The restored key gets no new version.
+    #[pg_test]
+    fn e47_restore_public_key_history()  -> Result<(), Box<dyn Error>> {
+        let schema = Spi::get_one::<String>("
+SELECT extnamespace::regnamespace::text FROM pg_extension 
+WHERE extname = 'pg_enigma';
+        ")?.ok_or("pg_enigma is not installed")?;
+        Spi::run(&format!(
+        "
+SET search_path = '';
+INSERT INTO {schema}._enigma_public_key_history(id, version, fingerprint) 
+VALUES (47, 1, 'F1'), (47, 2, 'F2');
+INSERT INTO {schema}._enigma_public_keys(id, fingerprint) VALUES (47, 'F2');
+RESET search_path;
+        "))? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT string_agg(format('%s:%s', version, fingerprint), ',' ORDER BY version)
+FROM enigma_key_history WHERE id = 47;
+        ")? {
+            info!("Key versions: {}", res);
+            if res.as_str() == "1:F1,2:F2" { return Ok(()); }
+        } 
+        Err("Should return restored key versions 1 and 2".into()) 
+    } 
+
+    /// enigma_admin stores public keys through the key functions, which
+    /// record the calling role
+    #[pg_test]
+    fn e48_admin_sets_public_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE TEMP TABLE testkey AS 
+SELECT pg_read_file('../../../test/public-key.asc') AS key;
+CREATE ROLE enigma_test_admin IN ROLE enigma_admin;
+GRANT SELECT ON testkey TO enigma_test_admin;
+SET ROLE enigma_test_admin;
+SELECT set_public_key(48, key) FROM testkey;
+RESET ROLE;
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT format('%s:%s', k.inserted_by, h.inserted_by) FROM enigma_keys k
+JOIN enigma_key_history h USING (id) WHERE id = 48;
+        ")? {
+            info!("Inserted by: {}", res);
+            if res.as_str() == "enigma_test_admin:enigma_test_admin" { 
+                return Ok(()); 
+            }
+        } 
+        Err("Key should be inserted by enigma_test_admin".into()) 
+    } 
+
+    /// enigma_admin can not write the keys table directly, bypassing the
+    /// replacement check and the key history
+    #[pg_test(error = "permission denied for table _enigma_public_keys")]
+    fn e49_admin_can_not_write_keys_table()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE ROLE enigma_test_admin IN ROLE enigma_admin;
+SET ROLE enigma_test_admin;
+UPDATE _enigma_public_keys SET fingerprint = 'forged';
+        ")? ; 
+        Err("Should refuse to write the keys table".into()) 
+    } 
+
+    /// Expired private key is forgotten at the end of the next 
+    /// transaction, without using it again
+    #[pg_test]
+    fn e50_expired_private_key_at_xact_end()  -> Result<(), Box<dyn Error>> {
+        let printed = psql("
+SELECT set_private_key_from_file(50, 
+    '../../../test/private-key.asc', 'Prueba123!', ttl => '1 second'); 
+\\! sleep 2
+SELECT 1;
+SELECT forget_private_key(50);
+        ")?;
+        if printed.contains("key 50: not set") {
+            return Ok(());
+        }
+        Err("Transaction end should forget the expired key".into()) 
+    } 
+
+    /// Preloaded vault private key 7 is only used by the granted roles
+    #[pg_test]
+    fn e51_preload_vault_private_keys()  -> Result<(), Box<dyn Error>> {
+        use crate::guc::PreloadPrivateKeys;
+        crate::preload::load_keys(PreloadPrivateKeys::Vault);
+        Spi::run("
+CREATE ROLE enigma_test_vault;
+CREATE ROLE enigma_test_no_vault;
+SELECT grant_vault_private_key(7, 'enigma_test_vault');
+SET ROLE enigma_test_vault;
+        ")?;
+        let granted = crate::vault::get(7)?.map(|k| k.priv_key_id());
+        Spi::run("
+RESET ROLE;
+SET ROLE enigma_test_no_vault;
+        ")?;
+        let refused = crate::vault::get(7)?.is_none();
+        Spi::run("
+RESET ROLE;
+SELECT forget_vault_private_key(7);
+        ")?;
+        if ! refused {
+            return Err("Should refuse the role without the grant".into());
+        }
+        if granted.is_some() {
+            return Ok(());
+        }
+        Err("Should load private key 7 in the vault".into()) 
+    } 
+
+    /// Values encrypted in one transaction with a KMS key share the data
+    /// key, wrapped by the KMS once
+    #[pg_test]
+    fn e52_kms_transaction_data_key()  -> Result<(), Box<dyn Error>> {
+        let port = kms_stub("s.enigma-test")?;
+        Spi::run(&format!(
+        "
+SET enigma.kms_url = 'http://127.0.0.1:{port}/v1/transit';
+SET enigma.kms_token = 's.enigma-test';
+CREATE TABLE testab ( a SERIAL, b Enigma(52));
+SELECT set_public_key(52, 'kms:enigma-test'); 
+INSERT INTO testab (b) VALUES ('one'::Text), ('two'::Text), ('three'::Text);
+RESET enigma.kms_url;
+        "))? ; 
+        let mut wrapped = Vec::new();
+        for a in 1..=3 {
+            let encrypted = Spi::get_one::<Enigma>(&format!(
+                "SELECT b FROM testab WHERE a = {a}"))?
+                .ok_or("No KMS record")?.to_string();
+            wrapped.push(encrypted.lines()
+                .skip_while(|line| *line != "enigma-test").nth(1)
+                .unwrap_or("").to_string());
+        }
+        wrapped.dedup();
+        if wrapped.len() != 1 || ! wrapped[0].starts_with("stub:v1:") {
+            return Err("Should wrap a single data key".into());
+        }
+        Spi::run(&format!(
+        "
+SET enigma.kms_url = 'http://127.0.0.1:{port}/v1/transit';
+        "))? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT string_agg(CAST(b AS Text), ',' ORDER BY a) FROM testab;
+        ")? {
+            if res.as_str() == "one,two,three" { return Ok(()); }
+        } 
+        Err("Should return decrypted strings".into()) 
+    } 
+
+    /// enigma_reader creates the secret key salt only through
+    /// set_secret_key(). enigma_key_id() reads the Esym header.
+    #[pg_test]
+    fn e53_reader_sets_secret_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE ROLE enigma_test_reader IN ROLE enigma_reader;
+SET ROLE enigma_test_reader;
+SELECT set_secret_key(53, 'my passphrase'); 
+RESET ROLE;
+CREATE TABLE testab ( a SERIAL, b Esym(53));
+INSERT INTO testab (b) VALUES ('my SYM test record'::Text);
+        ")? ; 
+        let created_by = Spi::get_one::<String>("
+SELECT created_by::text FROM _enigma_secret_keys WHERE id = 53;
+        ")?;
+        if created_by.as_deref() != Some("enigma_test_reader") {
+            return Err("Should record the reader as creator".into());
+        }
+        if let Some(res) = Spi::get_one::<i32>("
+SELECT enigma_key_id(b) FROM testab LIMIT 1;
+        ")? {
+            if res == 53 { return Ok(()); }
+        } 
+        Err("Should return secret key id 53".into()) 
+    } 
+
+    /// enigma_reader can not seed a KDF spec for a secret key
+    #[pg_test(error = "permission denied for table _enigma_secret_keys")]
+    fn e54_reader_can_not_insert_secret_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+CREATE ROLE enigma_test_reader IN ROLE enigma_reader;
+SET ROLE enigma_test_reader;
+INSERT INTO _enigma_secret_keys(id, kdf) VALUES (54, 'argon2id$m=1$t=1'); 
+        ")? ; 
+        Err("Should refuse to insert into _enigma_secret_keys".into()) 
+    } 
+
+    /// Expired key 2 and its expired encryption subkey are usable in 
+    /// the grace period
+    #[pg_test]
+    fn e55_expired_key_grace()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+SET enigma.key_expiry_grace = 36500;
+CREATE TABLE testab ( a SERIAL, b Enigma(2));
+SELECT set_public_key_from_file(2, '../../../test/expired-public-key.asc'); 
+INSERT INTO testab (b) VALUES ('my PGP grace record'::Text);
+SELECT set_private_key_from_file(2, 
+    '../../../test/private-key.asc', 'Prueba123!'); 
+        ")? ; 
+        let expired = Spi::get_one::<bool>("
+SELECT expired FROM enigma_keys WHERE id = 2;
+        ")?;
+        if let Some(res) = Spi::get_one::<Enigma>("
+SELECT b FROM testab LIMIT 1;
+        ")? {
+            if expired == Some(true) 
+            && res.value() == String::from("my PGP grace record") {
+                return Ok(());
+            }
+        } 
+        Err("Should return expired key and decrypted string".into()) 
+    } 
+
+    /// Keys set before 0.6.0 have no fingerprint and can not be swapped 
+    /// by set_public_key() either
+    #[pg_test(error = "Public key 56 already exists. Use replace_public_key() \
+to replace it")]
+    fn e56_refuse_legacy_key_replacement()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+INSERT INTO _enigma_public_keys(id, public_key) 
+SELECT 56, pg_read_file('../../../test/public-key.asc');
+SELECT set_public_key_from_file(56, '../../../test/alice_public.pem'); 
+        ")? ; 
+        Err("Should refuse to replace public key 56".into()) 
+    } 
+
+    /// The upgrade script stores the fingerprint of keys set before 0.6.0
+    #[pg_test]
+    fn e57_backfill_legacy_key()  -> Result<(), Box<dyn Error>> {
+        Spi::run(
+        "
+INSERT INTO _enigma_public_keys(id, public_key) 
+SELECT 57, pg_read_file('../../../test/public-key.asc');
+SELECT _enigma_backfill_public_key(57);
+        ")? ; 
+        if let Some(res) = Spi::get_one::<String>("
+SELECT format('%s:%s', fingerprint, algorithm) FROM enigma_keys 
+WHERE id = 57;
+        ")? {
+            info!("Key: {}", res);
+            if res.as_str() == 
+            "1801984A6FFFBF4FBE865BA2CB7D5DA21AF8B860:PGP RSA 1024" {
+                return Ok(());
+            }
+        } 
+        Err("Should return the key fingerprint and algorithm".into()) 
+    } 
+
+    /// Local stub of the Vault Transit API "wrapping" data keys with a
+    /// `stub:v1:` prefix. Returns the port it listens on.
+    fn kms_stub(token: &'static str) -> Result<u16, Box<dyn Error>> {
+        use serde_json::json;
+        use std::io::{BufRead,BufReader,Read,Write};
+        use std::net::TcpListener;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        std::thread::spawn(move || for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(&stream);
+            let mut request = String::new();
+            let mut line = String::new();
+            let mut length = 0;
+            let mut authorized = false;
+            while reader.read_line(&mut line).unwrap_or(0) > 0 
+            && line != "\r\n" {
+                match line.split_once(':') {
+                    Some((name, value)) 
+                    if name.eq_ignore_ascii_case("content-length") =>
+                        length = value.trim().parse().unwrap_or(0),
+                    Some((name, value)) 
+                    if name.eq_ignore_ascii_case("x-vault-token") =>
+                        authorized = value.trim() == token,
+                    _ if request.is_empty() => request = line.clone(),
+                    _ => ()
+                }
+                line.clear();
+            }
+            let mut body = vec![0u8; length];
+            if reader.read_exact(&mut body).is_err() {
+                continue;
+            }
+            let body: serde_json::Value = serde_json::from_slice(&body)
+                .unwrap_or_default();
+            let (status, data) = if ! authorized {
+                ("403 Forbidden", json!({ "errors": ["permission denied"] }))
+            } else if request.contains("/encrypt/enigma-test ") {
+                let plaintext = body["plaintext"].as_str().unwrap_or("");
+                ("200 OK", json!({ "data": { 
+                    "ciphertext": format!("stub:v1:{}", plaintext) } }))
+            } else if request.contains("/decrypt/enigma-test ") {
+                let wrapped = body["ciphertext"].as_str().unwrap_or("");
+                ("200 OK", json!({ "data": { 
+                    "plaintext": wrapped.trim_start_matches("stub:v1:") } }))
+            } else {
+                ("404 Not Found", json!({ "errors": [] }))
+            };
+            let data = data.to_string();
+            let _ = write!(&stream, "HTTP/1.1 {}\r\nContent-Type: \
+                application/json\r\nContent-Length: {}\r\nConnection: \
+                close\r\n\r\n{}", status, data.len(), data);
+        });
+        Ok(port)
+    }
+
 }
 
 /// This module is required by `cargo pgrx test` invocations.
@@ -406,7 +1919,11 @@ pub mod pg_test {
 
     pub fn postgresql_conf_options() -> Vec<&'static str> {
         // return any postgresql.conf settings that are required for your tests
-        vec![]
+        vec!["enigma.key_directory = '../../../test'",
+            "shared_preload_libraries = 'pg_enigma'",
+            // test/7.pem is unlocked at start
+            "enigma.preload_private_keys = 'backend'",
+            "enigma.passphrase_command = 'cat ../../../test/private-key.pass'"]
     }
 }
 
@@ -0,0 +1,143 @@
+use crate::PRIV_KEYS;
+use crate::guc::{PASSPHRASE_COMMAND,PRELOAD_PRIVATE_KEYS,PreloadPrivateKeys};
+use crate::key_file::key_directory;
+use crate::secret::Secret;
+use crate::vault;
+use pgrx::prelude::*;
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::process::{Command,Stdio};
+use zeroize::Zeroize;
+
+/// Private key file extensions: PEM and PGP armored keys
+const KEY_FILE_EXTENSIONS: &[&str] = &[ "pem", "asc" ];
+
+static mut PREV_SHMEM_STARTUP_HOOK: pg_sys::shmem_startup_hook_type = None;
+
+/// Loads the private keys in `enigma.key_directory` as set by
+/// `enigma.preload_private_keys`. Called from `_PG_init()`, after the
+/// vault shared memory is requested.
+/// Only possible when loaded from `shared_preload_libraries`: backends
+/// inherit the keys loaded by the postmaster.
+pub fn init() {
+    if ! unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+    match PRELOAD_PRIVATE_KEYS.get() {
+        PreloadPrivateKeys::Off => (),
+        PreloadPrivateKeys::Backend => load_keys(PreloadPrivateKeys::Backend),
+        PreloadPrivateKeys::Vault => unsafe {
+            // the vault exists once the shared memory is created
+            PREV_SHMEM_STARTUP_HOOK = pg_sys::shmem_startup_hook;
+            pg_sys::shmem_startup_hook = Some(preload_vault_keys);
+        }
+    }
+}
+
+/// Also called after a crash restart, when the vault is created again
+#[pg_guard]
+unsafe extern "C-unwind" fn preload_vault_keys() {
+    if let Some(prev) = PREV_SHMEM_STARTUP_HOOK {
+        prev();
+    }
+    load_keys(PreloadPrivateKeys::Vault);
+}
+
+/// Loads every `<id>.pem` or `<id>.asc` file in `enigma.key_directory`.
+/// Keys that can not be loaded are logged and skipped.
+pub fn load_keys(target: PreloadPrivateKeys) {
+    let files = match key_files() {
+        Ok(files) => files,
+        Err(e) => {
+            warning!("pg_enigma: could not preload private keys: {}", e);
+            return;
+        }
+    };
+    for (id, path) in files {
+        match load_key(id, &path, target) {
+            Ok(msg) => log!("pg_enigma: {} from {}", msg, path.display()),
+            Err(e) => warning!("pg_enigma: could not preload private key {}: \
+                {}", path.display(), e)
+        }
+    }
+}
+
+fn load_key(id: u32, path: &Path, target: PreloadPrivateKeys)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    let mut contents = fs::read_to_string(path)?;
+    let key = Secret::new(&contents);
+    contents.zeroize();
+    let pass = passphrase(id, path)?;
+    match target {
+        PreloadPrivateKeys::Vault if vault::is_enabled() =>
+            vault::store(id, key.as_str(), pass.as_str()),
+        PreloadPrivateKeys::Vault => Err("the private key vault is not \
+            available".into()),
+        _ => PRIV_KEYS.set(id, key.as_str(), pass.as_str(), None)
+    }
+}
+
+/// Key files in `enigma.key_directory` with their key id, sorted by id
+fn key_files()
+-> Result<Vec<(u32, PathBuf)>, Box<dyn std::error::Error + 'static>> {
+    let dir = key_directory()?
+        .ok_or("enigma.key_directory is not set")?;
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let is_key_file = path.extension().and_then(|e| e.to_str())
+            .is_some_and(|e| KEY_FILE_EXTENSIONS.contains(&e));
+        let id = path.file_stem().and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u32>().ok());
+        match id {
+            Some(id) if id > 0 && is_key_file && path.is_file() =>
+                files.push((id, path)),
+            _ => ()
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Passphrase printed by `enigma.passphrase_command`.
+/// Empty passphrase if the command is not set.
+fn passphrase(id: u32, path: &Path)
+-> Result<Secret, Box<dyn std::error::Error + 'static>> {
+    let command = match PASSPHRASE_COMMAND.get() {
+        Some(command) if ! command.is_empty() =>
+            command_line(command.to_str()?, id, path),
+        _ => return Ok(Secret::new(""))
+    };
+    let mut output = Command::new("/bin/sh").arg("-c").arg(&command)
+        .stdin(Stdio::null()).stderr(Stdio::inherit()).output()
+        .map_err(|e| format!("enigma.passphrase_command failed: {}", e))?;
+    if ! output.status.success() {
+        output.stdout.zeroize();
+        return Err(format!("enigma.passphrase_command failed: {}",
+            output.status).into());
+    }
+    let pass = std::str::from_utf8(&output.stdout)
+        .map(|out| Secret::new(out.lines().next().unwrap_or("")));
+    output.stdout.zeroize();
+    Ok(pass?)
+}
+
+/// Replaces `%p` (key file path), `%k` (key id) and `%%` in the command
+fn command_line(command: &str, id: u32, path: &Path) -> String {
+    let mut line = String::with_capacity(command.len());
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            line.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('p') => line.push_str(&path.to_string_lossy()),
+            Some('k') => line.push_str(&id.to_string()),
+            Some('%') => line.push('%'),
+            Some(other) => { line.push('%'); line.push(other); },
+            None => line.push('%')
+        }
+    }
+    line
+}
@@ -1,40 +1,127 @@
 use crate::common::{Decrypt,Plain};
 use crate::types::enigma::Enigma;
+use crate::types::enigma_ecc::Eecc;
 use crate::types::enigma_pgp::Epgp;
 use crate::types::enigma_rsa::Ersa;
-use crate::crypt::pgp::{pgp_decrypt,pgp_sec_key_from,pgp_sec_key_id};
-use crate::crypt::openssl::{rsa_decrypt,rsa_priv_key_from,rsa_key_id};
-use openssl::pkey::{PKey,Private};
+use crate::crypt::pgp::{
+    PGP_SEC_KEY_BEGIN,pgp_decrypt,pgp_sec_key_from,pgp_sec_key_from_bytes,
+    pgp_sec_key_id,pgp_sec_key_unlocked
+};
+#[cfg(feature = "pkcs11")]
+use crate::crypt::pkcs11::Pkcs11Key;
+use crate::secret::Secret;
+use crate::crypt::openssl::{
+    ecc_decrypt,ecc_supported,openssl_priv_key_from,
+    openssl_priv_key_from_der,priv_key_format,rsa_decrypt,rsa_key_id
+};
+use openssl::pkey::{Id,PKey,Private};
 use pgp::composed::SignedSecretKey;
 use pgrx::debug2;
+use zeroize::Zeroizing;
 
+/// Unlocked key material kinds, see `PrivKey::unlocked()`
+pub const UNLOCKED_PGP: u8 = 1;
+pub const UNLOCKED_DER: u8 = 2;
+
+/// Private keys are zeroized when dropped: rPGP zeroizes the secret key
+/// parameters, OpenSSL clears the private key numbers when freed.
 pub enum PrivKey {
-    /// PGP secret key
-    PGP(SignedSecretKey, String),
+    /// PGP secret key and its passphrase
+    PGP(SignedSecretKey, Secret),
     /// OpenSSL RSA
-    RSA(PKey<Private>)
+    RSA(PKey<Private>),
+    /// OpenSSL elliptic curve (NIST curves or X25519)
+    ECC(PKey<Private>),
+    /// RSA or EC (NIST curves) key kept in a PKCS#11 token
+    #[cfg(feature = "pkcs11")]
+    Pkcs11(Pkcs11Key)
 }
 
 impl PrivKey {
     /// Creates a `PrivKey` struct with the key obtained
-    /// from the `armored key` and the provided plain text password
+    /// from the `armored key` and the provided plain text password.
+    /// Unencrypted keys can be set with an empty password.
     pub fn new(armored: &str, pw: &str) 
     -> Result<Self, Box<dyn std::error::Error + 'static>> {
-        if let Ok(priv_key) = pgp_sec_key_from(armored) {
-            return Ok(PrivKey::PGP(priv_key, pw.to_string()));
+        if armored.contains(PGP_SEC_KEY_BEGIN) {
+            let priv_key = pgp_sec_key_from(armored).map_err(|e| 
+                format!("Could not read PGP armored private key: {}", e))?;
+            return Ok(PrivKey::PGP(priv_key, Secret::new(pw)));
+        }
+
+        if priv_key_format(armored).is_some() {
+            return Self::from_openssl(openssl_priv_key_from(armored, pw)?);
+        }
+
+        Err("Private key not recognized: not PGP armor nor PEM".into())
+    }
+
+    /// Creates a `PrivKey` struct with the key obtained from
+    /// binary `der`. Encrypted PKCS#8 keys require the password.
+    pub fn from_der(der: &[u8], pw: &str) 
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        Self::from_openssl(openssl_priv_key_from_der(der, pw)?)
+    }
+
+    /// Creates a `PrivKey` struct referencing the private key with
+    /// `label` in the PKCS#11 token in `slot`
+    #[cfg(feature = "pkcs11")]
+    pub fn pkcs11(slot: u64, label: &str, pin: &str)
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        Ok(PrivKey::Pkcs11(Pkcs11Key::new(slot, label, pin)?))
+    }
+
+    #[cfg(not(feature = "pkcs11"))]
+    pub fn pkcs11(_slot: u64, _label: &str, _pin: &str)
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        Err("pg_enigma was built without PKCS#11 support \
+            (pkcs11 feature)".into())
+    }
+
+    /// Creates a `PrivKey` struct from the `PrivKey::unlocked()` key
+    /// material of `kind`
+    pub fn from_unlocked(kind: u8, bytes: &[u8])
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        match kind {
+            UNLOCKED_PGP => Ok(PrivKey::PGP(pgp_sec_key_from_bytes(bytes)?,
+                Secret::new(""))),
+            UNLOCKED_DER => Self::from_der(bytes, ""),
+            _ => Err(format!("Unknown unlocked key kind: {}", kind).into())
         }
+    }
 
-        if let Ok(priv_key) = rsa_priv_key_from(armored, pw) {
-            return Ok(PrivKey::RSA(priv_key));
+    /// Key material without passphrase protection and its kind: binary
+    /// PGP secret key or PKCS#8 DER. PKCS#11 keys can not be unlocked.
+    pub fn unlocked(&self)
+    -> Result<(u8, Zeroizing<Vec<u8>>), Box<dyn std::error::Error + 'static>> {
+        match self {
+            PrivKey::PGP(k, pass) => Ok((UNLOCKED_PGP,
+                Zeroizing::new(pgp_sec_key_unlocked(k, pass.as_str())?))),
+            PrivKey::RSA(k) | PrivKey::ECC(k) => Ok((UNLOCKED_DER,
+                Zeroizing::new(k.private_key_to_pkcs8()?))),
+            #[cfg(feature = "pkcs11")]
+            PrivKey::Pkcs11(_) => 
+                Err("PKCS#11 private keys never leave the token".into())
         }
+    }
 
-        Err("key not recognized".into())
+    fn from_openssl(key: PKey<Private>)
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        match key.id() {
+            Id::RSA => Ok(PrivKey::RSA(key)),
+            id if ecc_supported(id) => Ok(PrivKey::ECC(key)),
+            id => Err(format!("Unsupported private key type: {:?}", id)
+                .into())
+        }
     }
 
     pub fn priv_key_id(&self) -> String {
         match self {
             PrivKey::PGP(k,_) => pgp_sec_key_id(k),
-            PrivKey::RSA(k) => rsa_key_id(k)
+            PrivKey::RSA(k) => rsa_key_id(k),
+            PrivKey::ECC(k) => rsa_key_id(k),
+            #[cfg(feature = "pkcs11")]
+            PrivKey::Pkcs11(k) => k.key_id()
         }
     }
 }
@@ -49,19 +136,39 @@ impl Decrypt<Enigma> for PrivKey {
         match self {
             PrivKey::PGP(key,pass) => {
                 debug2!("Decrypt: PGP key");
-                if let Enigma::PGP(_,msg) = enigma {
-                    Ok(Enigma::plain(pgp_decrypt(key, pass.clone(), msg)?))
-                } else {
-                    Err("Message is not PGP encrypted.".into())
+                match enigma {
+                    Enigma::PGP(_,_,msg) | Enigma::PGPMulti(_,_,_,msg) => Ok(
+                        Enigma::plain(pgp_decrypt(key, pass.as_str(), msg)?)),
+                    _ => Err("Message is not PGP encrypted.".into())
                 }
             },
             PrivKey::RSA(key) => {
                 debug2!("Decrypt: RSA key");
-                if let Enigma::RSA(_,msg) = enigma {
-                    Ok(Enigma::plain(rsa_decrypt(key, msg)?))
+                if let Enigma::RSA(id,_,msg) = enigma {
+                    Ok(Enigma::plain(rsa_decrypt(key, id, msg)?))
                 } else {
                     Err("Message is not RSA encrypted.".into())
                 }
+            },
+            PrivKey::ECC(key) => {
+                debug2!("Decrypt: ECC key");
+                if let Enigma::ECC(_,_,msg) = enigma {
+                    Ok(Enigma::plain(ecc_decrypt(key, msg)?))
+                } else {
+                    Err("Message is not ECC encrypted.".into())
+                }
+            },
+            #[cfg(feature = "pkcs11")]
+            PrivKey::Pkcs11(key) => {
+                debug2!("Decrypt: PKCS#11 key");
+                match enigma {
+                    Enigma::RSA(id,_,msg) if key.is_rsa() => 
+                        Ok(Enigma::plain(key.rsa_decrypt(id, msg)?)),
+                    Enigma::ECC(_,_,msg) if key.is_ecc() => 
+                        Ok(Enigma::plain(key.ecc_decrypt(msg)?)),
+                    _ => Err("Message is not encrypted with the PKCS#11 \
+                        key type.".into())
+                }
             }
         }
     }
@@ -77,10 +184,10 @@ impl Decrypt<Epgp> for PrivKey {
         match self {
             PrivKey::PGP(key,pass) => {
                 debug2!("Decrypt: PGP key");
-                if let Epgp::PGP(_,msg) = enigma {
-                    Ok(Epgp::plain(pgp_decrypt(key, pass.clone(), msg)?))
-                } else {
-                    Err("Message is not PGP encrypted.".into())
+                match enigma {
+                    Epgp::PGP(_,_,msg) | Epgp::PGPMulti(_,_,_,msg) => Ok(
+                        Epgp::plain(pgp_decrypt(key, pass.as_str(), msg)?)),
+                    _ => Err("Message is not PGP encrypted.".into())
                 }
             },
             _ => Err("Key is not PGP".into())
@@ -98,8 +205,17 @@ impl Decrypt<Ersa> for PrivKey {
         match self {
             PrivKey::RSA(key) => {
                 debug2!("Decrypt: RSA key");
-                if let Ersa::RSA(_,msg) = enigma {
-                    Ok(Ersa::plain(rsa_decrypt(key, msg)?))
+                if let Ersa::RSA(id,_,msg) = enigma {
+                    Ok(Ersa::plain(rsa_decrypt(key, id, msg)?))
+                } else {
+                    Err("Message is not RSA encrypted.".into())
+                }
+            },
+            #[cfg(feature = "pkcs11")]
+            PrivKey::Pkcs11(key) if key.is_rsa() => {
+                debug2!("Decrypt: PKCS#11 RSA key");
+                if let Ersa::RSA(id,_,msg) = enigma {
+                    Ok(Ersa::plain(key.rsa_decrypt(id, msg)?))
                 } else {
                     Err("Message is not RSA encrypted.".into())
                 }
@@ -109,4 +225,34 @@ impl Decrypt<Ersa> for PrivKey {
     }
 }
 
+impl Decrypt<Eecc> for PrivKey {
+    fn decrypt(&self, enigma: Eecc) 
+    -> Result<Eecc, Box<dyn std::error::Error + 'static>> {
+        if enigma.is_plain() { 
+             return Err("Already decrypted message".into());
+        }
+
+        match self {
+            PrivKey::ECC(key) => {
+                debug2!("Decrypt: ECC key");
+                if let Eecc::ECC(_,_,msg) = enigma {
+                    Ok(Eecc::plain(ecc_decrypt(key, msg)?))
+                } else {
+                    Err("Message is not ECC encrypted.".into())
+                }
+            },
+            #[cfg(feature = "pkcs11")]
+            PrivKey::Pkcs11(key) if key.is_ecc() => {
+                debug2!("Decrypt: PKCS#11 ECC key");
+                if let Eecc::ECC(_,_,msg) = enigma {
+                    Ok(Eecc::plain(key.ecc_decrypt(msg)?))
+                } else {
+                    Err("Message is not ECC encrypted.".into())
+                }
+            },
+            _ => Err("Key is not ECC".into())
+        }
+    }
+}
+
 
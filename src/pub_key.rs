@@ -1,34 +1,87 @@
-use crate::common::{Encrypt,IsEncrypted};
+use crate::common::{Encrypt,IsEncrypted,key_print_from};
 use crate::types::enigma::Enigma;
+use crate::types::enigma_ecc::Eecc;
 use crate::types::enigma_pgp::Epgp;
 use crate::types::enigma_rsa::Ersa;
-use crate::crypt::pgp::{pgp_encrypt,pgp_pub_key_from,pgp_pub_key_id};
-use crate::crypt::openssl::{rsa_encrypt,rsa_pub_key_from,rsa_key_id};
+use crate::crypt::pgp::{
+    pgp_algorithm,pgp_encrypt,pgp_encryption_subkeys,pgp_expiration,
+    pgp_fingerprint,pgp_key_dates,pgp_pub_key_from,pgp_pub_key_id,pgp_revoked
+};
+use crate::crypt::openssl::{
+    ecc_encrypt,ecc_pub_key_from,kms_encrypt,openssl_algorithm,
+    openssl_fingerprint,rsa_encrypt,rsa_pub_key_from,rsa_key_id
+};
+use crate::key_provider::{KMS_KEY_PREFIX,key_provider,kms_key_ref};
+use crate::rotate::enigma_type_oids;
+use crate::schema::qualified;
+use crate::guc::{KEY_EXPIRY_GRACE,KEY_EXPIRY_WARNING,RSA_PADDING};
+use crate::invalidate::set_keys_relid;
+use openssl::hash::{MessageDigest,hash};
 use openssl::pkey::{PKey,Public};
 use pgp::composed::SignedPublicKey;
 use pgrx::datum::DatumWithOid;
-use pgrx::{PgBuiltInOids,Spi};
+use pgrx::{ereport,pg_sys,warning,PgBuiltInOids,PgSqlErrorCode,Spi};
+use std::time::{SystemTime,UNIX_EPOCH};
 
-// TODO:  Other OpenSSL supported key types (elyptic curves, etc.)
+const SECONDS_PER_DAY: i64 = 86400;
 
+/// Public keys keep the first 64 bits of their fingerprint, computed once,
+/// for the Key Print header of every value they encrypt
 pub enum PubKey {
     /// PGP public key
-    PGP(SignedPublicKey),
+    PGP(SignedPublicKey, Option<u64>),
     /// OpenSSL RSA
-    RSA(PKey<Public>)
+    RSA(PKey<Public>, Option<u64>),
+    /// OpenSSL elliptic curve (NIST curves or X25519)
+    ECC(PKey<Public>, Option<u64>),
+    /// Key name in the key management service, see `KeyProvider`
+    KMS(String, Option<u64>)
+}
+
+/// Public key metadata stored in table _enigma_public_keys
+pub struct KeyInfo {
+    pub fingerprint: String,
+    /// algorithm and key size or curve
+    pub algorithm: String,
+    /// Unix timestamp, only PGP keys have creation time
+    pub created_at: Option<i64>,
+    /// Unix timestamp, only PGP keys can expire
+    pub expires_at: Option<i64>
 }
 
 impl PubKey {
     /// Creates a `PubKey` struct with the key obtained
     /// from the `armored key`
     pub fn new(armored: &str) 
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        let key = PubKey::parse(armored)?;
+        key.check_usable();
+        key.warn_expiry();
+        Ok(key)
+    }
+
+    /// Same as `new()` without checking if the key is expired or revoked
+    pub fn parse(armored: &str) 
     -> Result<Self, Box<dyn std::error::Error + 'static>> {
         if let Ok(pub_key) = pgp_pub_key_from(armored) {
-            return Ok(PubKey::PGP(pub_key));
+            return Ok(PubKey::PGP(pub_key, None).with_print());
         }
 
         if let Ok(pub_key) = rsa_pub_key_from(armored) {
-            return Ok(PubKey::RSA(pub_key));
+            return Ok(PubKey::RSA(pub_key, None).with_print());
+        }
+
+        if let Ok(pub_key) = ecc_pub_key_from(armored) {
+            return Ok(PubKey::ECC(pub_key, None).with_print());
+        }
+
+        if armored.trim_start().starts_with(KMS_KEY_PREFIX) {
+            return match kms_key_ref(armored) {
+                Some(key_ref) => Ok(
+                    PubKey::KMS(key_ref.to_string(), None).with_print()),
+                None => Err("KMS key name can only have letters, digits, \
+                    '-', '_' and '.'".into())
+            };
         }
 
         Err("Key not recognized".into())
@@ -36,11 +89,131 @@ impl PubKey {
 
     pub fn pub_key_id(&self) -> String {
         match self {
-            PubKey::PGP(k) => pgp_pub_key_id(k),
-            PubKey::RSA(k) => rsa_key_id(k)
+            PubKey::PGP(k, _) => pgp_pub_key_id(k),
+            PubKey::RSA(k, _) => rsa_key_id(k),
+            PubKey::ECC(k, _) => rsa_key_id(k),
+            PubKey::KMS(key_ref, _) => format!("KMS key {}", key_ref)
+        }
+    }
+
+    /// Raises an error if the PGP key is revoked or if it is expired 
+    /// beyond the `enigma.key_expiry_grace` days, and if all its
+    /// encryption subkeys are.
+    /// SQLSTATE 55000 (object not in prerequisite state).
+    pub fn check_usable(&self) {
+        let key = match self {
+            PubKey::PGP(k, _) => k,
+            _ => return // OpenSSL keys don't expire
+        };
+        if pgp_revoked(key) {
+            ereport!(ERROR,
+                PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+                format!("PGP public key {} is revoked", pgp_pub_key_id(key)));
+        }
+        if let Some((expires, date)) = pgp_expiration(key) {
+            if expires < expired_before() {
+                ereport!(ERROR,
+                    PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+                    format!("PGP public key {} expired on {}", 
+                        pgp_pub_key_id(key), date),
+                    format!("enigma.key_expiry_grace is {} days", 
+                        KEY_EXPIRY_GRACE.get()));
+            }
+        }
+        // messages are encrypted to the newest usable subkey
+        let subkeys = pgp_encryption_subkeys(key);
+        if subkeys.iter().any(|s| s.usable(expired_before())) {
+            return;
+        }
+        match subkeys.first() {
+            Some(s) if s.revoked => ereport!(ERROR,
+                PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+                format!("PGP encryption subkey {} is revoked", s.key_id())),
+            Some(s) => if let Some((_, date)) = &s.expires {
+                ereport!(ERROR,
+                    PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+                    format!("PGP encryption subkey {} expired on {}", 
+                        s.key_id(), date),
+                    format!("enigma.key_expiry_grace is {} days", 
+                        KEY_EXPIRY_GRACE.get()));
+            },
+            None => () // no encryption subkeys
+        }
+    }
+
+    /// Warns if the PGP key is expired (in the grace period) or expires
+    /// within the next `enigma.key_expiry_warning` days
+    pub fn warn_expiry(&self) {
+        let key = match self {
+            PubKey::PGP(k, _) => k,
+            _ => return
+        };
+        if let Some((expires, date)) = pgp_expiration(key) {
+            let now = now();
+            if expires < now {
+                warning!("PGP public key {} expired on {}, \
+                    encrypting in the grace period", pgp_pub_key_id(key), date);
+            } else if expires - now 
+            < KEY_EXPIRY_WARNING.get() as i64 * SECONDS_PER_DAY {
+                warning!("PGP public key {} expires on {}", 
+                    pgp_pub_key_id(key), date);
+            }
         }
     }
 
+    /// First 64 bits of the key fingerprint, for the Key Print header
+    pub fn key_print(&self) -> Option<u64> {
+        match self {
+            PubKey::PGP(_, print) | PubKey::RSA(_, print) 
+            | PubKey::ECC(_, print) | PubKey::KMS(_, print) => *print
+        }
+    }
+
+    /// Same key with its Key Print computed from the fingerprint
+    fn with_print(mut self) -> Self {
+        let key_print = self.info().ok()
+            .and_then(|i| key_print_from(&i.fingerprint));
+        match &mut self {
+            PubKey::PGP(_, print) | PubKey::RSA(_, print) 
+            | PubKey::ECC(_, print) | PubKey::KMS(_, print) => 
+                *print = key_print
+        }
+        self
+    }
+
+    /// Key metadata parsed from the key
+    pub fn info(&self)
+    -> Result<KeyInfo, Box<dyn std::error::Error + 'static>> {
+        match self {
+            PubKey::PGP(k, _) => {
+                let (created, expires) = pgp_key_dates(k);
+                Ok(KeyInfo {
+                    fingerprint: pgp_fingerprint(k),
+                    algorithm: pgp_algorithm(k),
+                    created_at: Some(created),
+                    expires_at: expires
+                })
+            },
+            PubKey::RSA(k, _) | PubKey::ECC(k, _) => Ok(KeyInfo {
+                fingerprint: openssl_fingerprint(k)?,
+                algorithm: openssl_algorithm(k),
+                created_at: None,
+                expires_at: None
+            }),
+            PubKey::KMS(key_ref, _) => {
+                let reference = format!("{}{}", KMS_KEY_PREFIX, key_ref);
+                let digest = hash(MessageDigest::sha256(), 
+                    reference.as_bytes())?;
+                Ok(KeyInfo {
+                    fingerprint: digest.iter()
+                        .map(|b| format!("{:02X}", b)).collect(),
+                    algorithm: String::from("KMS"),
+                    created_at: None,
+                    expires_at: None
+                })
+            }
+        }
+    }
 }
 
 impl Encrypt<Enigma> for PubKey {
@@ -51,13 +224,27 @@ impl Encrypt<Enigma> for PubKey {
         }
 
         match self {
-            PubKey::PGP(pub_key) => {
-                let encrypted = pgp_encrypt(pub_key, msg.to_string())?;
-                Ok(Enigma::pgp(id, encrypted))
+            PubKey::PGP(pub_key, _) => {
+                self.check_usable();
+                let encrypted = pgp_encrypt(pub_key, expired_before(), 
+                    msg.to_string())?;
+                Ok(Enigma::pgp(id, self.key_print(), encrypted))
+            },
+            PubKey::RSA(pub_key, _) => {
+                let encrypted = rsa_encrypt(pub_key, id, msg.to_string(),
+                    RSA_PADDING.get().into())?;
+                Ok(Enigma::rsa(id, self.key_print(), encrypted))
             },
-            PubKey::RSA(pub_key) => {
-                let encrypted = rsa_encrypt(pub_key, msg.to_string())?;
-                Ok(Enigma::rsa(id, encrypted))
+            PubKey::ECC(pub_key, _) => {
+                let encrypted = ecc_encrypt(pub_key, msg.to_string())?;
+                Ok(Enigma::ecc(id, self.key_print(), encrypted))
+            },
+            PubKey::KMS(key_ref, _) => {
+                let provider = key_provider()?
+                    .ok_or("enigma.kms_url is not set")?;
+                let encrypted = kms_encrypt(key_ref, msg.to_string(), 
+                    || provider.data_key(key_ref))?;
+                Ok(Enigma::kms(id, self.key_print(), encrypted))
             }
         }
     }
@@ -71,9 +258,11 @@ impl Encrypt<Epgp> for PubKey {
         }
 
         match self {
-            PubKey::PGP(pub_key) => {
-                let encrypted = pgp_encrypt(pub_key, msg.to_string())?;
-                Ok(Epgp::pgp(id, encrypted))
+            PubKey::PGP(pub_key, _) => {
+                self.check_usable();
+                let encrypted = pgp_encrypt(pub_key, expired_before(), 
+                    msg.to_string())?;
+                Ok(Epgp::pgp(id, self.key_print(), encrypted))
             },
             _ => Err("Key is not PGP".into())
         }
@@ -88,52 +277,187 @@ impl Encrypt<Ersa> for PubKey {
         }
 
         match self {
-            PubKey::RSA(pub_key) => {
-                let encrypted = rsa_encrypt(pub_key, msg.to_string())?;
-                Ok(Ersa::rsa(id, encrypted))
+            PubKey::RSA(pub_key, _) => {
+                let encrypted = rsa_encrypt(pub_key, id, msg.to_string(),
+                    RSA_PADDING.get().into())?;
+                Ok(Ersa::rsa(id, self.key_print(), encrypted))
             },
             _ => Err("Key is not RSA".into())
         }
     }
 }
 
+impl Encrypt<Eecc> for PubKey {
+    fn encrypt(&self, id: u32, msg: Eecc) 
+    -> Result<Eecc, Box<dyn std::error::Error + 'static>> {
+        if msg.is_encrypted() { 
+             return Err("Nested encryption not supported".into());
+        }
+
+        match self {
+            PubKey::ECC(pub_key, _) => {
+                let encrypted = ecc_encrypt(pub_key, msg.to_string())?;
+                Ok(Eecc::ecc(id, self.key_print(), encrypted))
+            },
+            _ => Err("Key is not ECC".into())
+        }
+    }
+}
+
+/// Current time as Unix timestamp
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// Keys that expired before this Unix timestamp are beyond the
+/// `enigma.key_expiry_grace` days
+pub fn expired_before() -> i64 {
+    now() - KEY_EXPIRY_GRACE.get() as i64 * SECONDS_PER_DAY
+}
+
 /// Get the public key from the keys table
 /// id is `i32` because Postgres `integer` is signed integer
 pub fn get_public_key(id: i32) -> Result<Option<String>, pgrx::spi::Error> {
-    let query = "SELECT public_key FROM _enigma_public_keys WHERE id = $1";
+    let query = format!("SELECT public_key, tableoid FROM {} WHERE id = $1",
+        qualified("_enigma_public_keys")?);
     let args = unsafe { 
         [ DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()) ]
     };
     Spi::connect(|client| {
-        let tuple_table = client.select(query, Some(1), &args)?;
+        let tuple_table = client.select(&query, Some(1), &args)?;
         if tuple_table.len() == 0 {
             Ok(None)
         } else {
-            tuple_table.first().get_one::<String>()
+            let (key, relid) = tuple_table.first()
+                .get_two::<String, pg_sys::Oid>()?;
+            if let Some(relid) = relid {
+                set_keys_relid(relid);
+            }
+            Ok(key)
         }
     })
 
 }
 
-/// Inserts the armored public key as text in table _enigma_public_keys
-/// id is `i32` because Postgres `integer` is signed integer
-pub fn insert_public_key(id: i32, key: &str)
+/// Fingerprint of the key with `id` in table _enigma_public_keys.
+/// `None` if there is no such key.
+pub fn get_key_fingerprint(id: i32) 
 -> Result<Option<String>, pgrx::spi::Error> {
+    let args = unsafe {
+        [ DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()) ]
+    };
+    Spi::get_one_with_args(&format!(
+        "SELECT COALESCE(fingerprint, '') FROM {} WHERE id = $1",
+        qualified("_enigma_public_keys")?), &args)
+}
+
+/// Inserts the armored public key as text in table _enigma_public_keys,
+/// along with the key metadata.
+/// id is `i32` because Postgres `integer` is signed integer
+/// `name` replaces the key name when provided.
+/// `inserted_by` is the role calling the SECURITY DEFINER key function.
+pub fn insert_public_key(id: i32, key: &str, name: Option<&str>,
+info: &KeyInfo) -> Result<Option<String>, pgrx::spi::Error> {
     // create_key_table()?;
     let args = unsafe {
         [
             DatumWithOid::new(id,  PgBuiltInOids::INT4OID.value()),
             DatumWithOid::new(key, PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(name, PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(info.fingerprint.as_str(),
+                PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(info.algorithm.as_str(),
+                PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(info.created_at, PgBuiltInOids::INT8OID.value()),
+            DatumWithOid::new(info.expires_at, PgBuiltInOids::INT8OID.value()),
+            DatumWithOid::new(unsafe { pg_sys::GetOuterUserId() },
+                PgBuiltInOids::OIDOID.value()),
         ]
     };
-    Spi::get_one_with_args(
-        r#"INSERT INTO _enigma_public_keys(id, public_key)
-           VALUES ($1, $2)
+    Spi::get_one_with_args(&format!(
+        r#"INSERT INTO {}(id, public_key, name,
+            fingerprint, algorithm, created_at, expires_at, inserted_by)
+           VALUES ($1, $2, $3, $4, $5, to_timestamp($6), to_timestamp($7),
+               (SELECT rolname FROM pg_catalog.pg_roles WHERE oid = $8))
            ON CONFLICT(id)
-           DO UPDATE SET public_key=$2
+           DO UPDATE SET public_key=$2,
+               name=COALESCE($3, _enigma_public_keys.name),
+               fingerprint=$4, algorithm=$5,
+               created_at=to_timestamp($6), expires_at=to_timestamp($7),
+               inserted_by=EXCLUDED.inserted_by, inserted_at=now()
            RETURNING 'Public key set'"#,
-         &args
-    )
+        qualified("_enigma_public_keys")?), &args)
+}
+
+/// Stores the metadata of a key that has no fingerprint, like the keys
+/// set before 0.6.0. Returns `None` if the key already had one.
+pub fn update_key_info(id: i32, info: &KeyInfo) 
+-> Result<Option<String>, pgrx::spi::Error> {
+    let args = unsafe {
+        [
+            DatumWithOid::new(id,  PgBuiltInOids::INT4OID.value()),
+            DatumWithOid::new(info.fingerprint.as_str(),
+                PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(info.algorithm.as_str(),
+                PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(info.created_at, PgBuiltInOids::INT8OID.value()),
+            DatumWithOid::new(info.expires_at, PgBuiltInOids::INT8OID.value()),
+        ]
+    };
+    Spi::get_one_with_args(&format!(
+        r#"UPDATE {} SET fingerprint=$2, algorithm=$3,
+               created_at=to_timestamp($4), expires_at=to_timestamp($5)
+           WHERE id = $1 AND fingerprint IS NULL
+           RETURNING 'Public key metadata set'"#,
+        qualified("_enigma_public_keys")?), &args)
+}
+
+/// Deletes the key from table _enigma_public_keys
+/// Returns the deleted key id, `None` if there was no such key.
+pub fn delete_public_key_row(id: i32) -> Result<Option<i32>, pgrx::spi::Error> {
+    let args = unsafe {
+        [ DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()) ]
+    };
+    Spi::get_one_with_args(&format!(
+        "DELETE FROM {} WHERE id = $1 RETURNING id",
+        qualified("_enigma_public_keys")?), &args)
+}
+
+/// Columns (`table.column`) of an Enigma type with key `id` type modifier
+pub fn key_columns(id: i32) 
+-> Result<Vec<String>, Box<dyn std::error::Error + 'static>> {
+    let args = unsafe {
+        [
+            DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()),
+            DatumWithOid::new(enigma_type_oids(), 
+                PgBuiltInOids::OIDARRAYOID.value()),
+        ]
+    };
+    let columns = Spi::get_one_with_args::<Vec<String>>(
+        r#"SELECT array_agg(format('%s.%I', attrelid::regclass, attname) 
+               ORDER BY attrelid, attnum)
+           FROM pg_attribute
+           WHERE atttypmod = $1 AND atttypid = ANY($2)
+           AND attnum > 0 AND NOT attisdropped"#,
+        &args
+    )?;
+    Ok(columns.unwrap_or_default())
+}
+
+/// Key id of the key named `name` in table _enigma_public_keys
+pub fn get_key_id(name: &str) -> Result<Option<i32>, pgrx::spi::Error> {
+    let args = unsafe {
+        [ DatumWithOid::new(name, PgBuiltInOids::TEXTOID.value()) ]
+    };
+    Spi::get_one_with_args(&format!("SELECT id FROM {} WHERE name = $1",
+        qualified("_enigma_public_keys")?), &args)
+}
+
+/// Key id of the key named `name`. Error if there is no such key.
+pub fn key_id_from_name(name: &str)
+-> Result<i32, Box<dyn std::error::Error + 'static>> {
+    get_key_id(name)?.ok_or(format!("No key named '{}'", name).into())
 }
 
 
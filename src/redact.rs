@@ -0,0 +1,214 @@
+use pgrx::prelude::*;
+use std::ffi::{CStr,CString};
+use std::os::raw::c_char;
+
+/// Functions whose arguments carry private or secret keys and
+/// passphrases, and the `enigma.kms_token` setting.
+/// Matched as a prefix, so `set_private_key` also covers
+/// `set_private_key_from_file()`.
+const KEY_FUNCTIONS: &[&str] = &[ 
+    "set_private_key", "set_vault_private_key", "set_secret_key",
+    "enigma.kms_token" 
+];
+
+/// Replacement for redacted string literals
+const REDACTED: &str = "'********'";
+
+static mut PREV_EMIT_LOG_HOOK: pg_sys::emit_log_hook_type = None;
+static mut PREV_POST_PARSE_ANALYZE_HOOK: pg_sys::post_parse_analyze_hook_type
+    = None;
+
+/// Installs the hooks scrubbing key function arguments from the server log
+/// and `pg_stat_activity`. Called from `_PG_init()`
+pub fn init() {
+    unsafe {
+        PREV_EMIT_LOG_HOOK = pg_sys::emit_log_hook;
+        pg_sys::emit_log_hook = Some(redact_emit_log);
+        PREV_POST_PARSE_ANALYZE_HOOK = pg_sys::post_parse_analyze_hook;
+        pg_sys::post_parse_analyze_hook = Some(redact_post_parse_analyze);
+    }
+}
+
+/// Returns the query with all string literals redacted if it calls any of
+/// the key functions. `None` if there is nothing to redact.
+pub fn redact(query: &str) -> Option<String> {
+    if ! calls_key_function(query) {
+        return None;
+    }
+    let chars: Vec<char> = query.chars().collect();
+    let mut out = String::with_capacity(query.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\'' => {
+                let escaped = i > 0 && (chars[i-1] == 'E' || chars[i-1] == 'e')
+                    && (i < 2 || ! is_ident_char(chars[i-2]));
+                i = skip_quoted(&chars, i + 1, escaped);
+                out.push_str(REDACTED);
+            },
+            '"' => { // quoted identifier is copied as is
+                let end = skip_identifier(&chars, i + 1);
+                out.extend(&chars[i..end]);
+                i = end;
+            },
+            '$' if i == 0 || ! is_ident_char(chars[i-1]) => {
+                match dollar_tag(&chars, i) {
+                    Some(tag) => {
+                        i = skip_dollar_quoted(&chars, i + tag.len(), &tag);
+                        out.push_str(REDACTED);
+                    },
+                    None => { // positional parameter
+                        out.push(c);
+                        i += 1;
+                    }
+                }
+            },
+            _ => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    Some(out)
+}
+
+/*********************
+ * PRIVATE FUNCTIONS *
+ * *******************/
+
+fn calls_key_function(query: &str) -> bool {
+    let lower = query.to_lowercase();
+    KEY_FUNCTIONS.iter().any(|f| lower.contains(f))
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Position after the closing quote of a `'` literal starting at `start`
+fn skip_quoted(chars: &[char], start: usize, escaped: bool) -> usize {
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if escaped => i += 2,
+            '\'' if chars.get(i + 1) == Some(&'\'') => i += 2,
+            '\'' => return i + 1,
+            _ => i += 1
+        }
+    }
+    chars.len() // unterminated literal: redact to the end
+}
+
+/// Position after the closing `"` of a quoted identifier
+fn skip_identifier(chars: &[char], start: usize) -> usize {
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '"' if chars.get(i + 1) == Some(&'"') => i += 2,
+            '"' => return i + 1,
+            _ => i += 1
+        }
+    }
+    chars.len()
+}
+
+/// `$tag$` delimiter starting at `start`, `None` if it is a parameter
+fn dollar_tag(chars: &[char], start: usize) -> Option<String> {
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '$' => return Some(chars[start..=i].iter().collect()),
+            c if c.is_ascii_digit() && i == start + 1 => return None,
+            c if c.is_alphanumeric() || c == '_' => i += 1,
+            _ => return None
+        }
+    }
+    None
+}
+
+/// Position after the closing `tag` of a dollar quoted literal
+fn skip_dollar_quoted(chars: &[char], start: usize, tag: &str) -> usize {
+    let tag: Vec<char> = tag.chars().collect();
+    let mut i = start;
+    while i + tag.len() <= chars.len() {
+        if chars[i..i + tag.len()] == tag[..] {
+            return i + tag.len();
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+/// Redacts the `palloc`'d string in place, returns true if redacted
+unsafe fn redact_cstr(ptr: &mut *mut c_char) -> bool {
+    if ptr.is_null() {
+        return false;
+    }
+    let text = CStr::from_ptr(*ptr).to_string_lossy();
+    match redact(&text).and_then(|r| CString::new(r).ok()) {
+        Some(redacted) => {
+            *ptr = pg_sys::pstrdup(redacted.as_ptr());
+            true
+        },
+        None => false
+    }
+}
+
+/// Scrubs key function arguments from messages sent to the server log.
+/// The statement line is hidden when the current query calls a key
+/// function, since Postgres prints it after this hook.
+#[pg_guard]
+unsafe extern "C-unwind" fn redact_emit_log(edata: *mut pg_sys::ErrorData) {
+    if let Some(e) = edata.as_mut() {
+        let mut redacted = redact_cstr(&mut e.message);
+        redacted |= redact_cstr(&mut e.detail);
+        redacted |= redact_cstr(&mut e.detail_log);
+        redacted |= redact_cstr(&mut e.context);
+        redacted |= redact_cstr(&mut e.internalquery);
+        if redacted || (! pg_sys::debug_query_string.is_null()
+        && calls_key_function(
+            &CStr::from_ptr(pg_sys::debug_query_string).to_string_lossy())) {
+            e.hide_stmt = true;
+        }
+    }
+    if let Some(prev) = PREV_EMIT_LOG_HOOK {
+        prev(edata);
+    }
+}
+
+/// Reports the redacted query text to `pg_stat_activity`. Postgres has
+/// already reported the raw text before parsing it, so it is visible
+/// until this runs.
+unsafe fn redact_activity(pstate: *mut pg_sys::ParseState) {
+    if pstate.is_null() || (*pstate).p_sourcetext.is_null()
+    || (*pstate).p_sourcetext != pg_sys::debug_query_string {
+        return; // not the top level query
+    }
+    let text = CStr::from_ptr((*pstate).p_sourcetext).to_string_lossy();
+    if let Some(redacted) = redact(&text).and_then(|r| CString::new(r).ok()) {
+        pg_sys::pgstat_report_activity(
+            pg_sys::BackendState::STATE_RUNNING, redacted.as_ptr());
+    }
+}
+
+#[cfg(feature = "pg13")]
+#[pg_guard]
+unsafe extern "C-unwind" fn redact_post_parse_analyze(
+pstate: *mut pg_sys::ParseState, query: *mut pg_sys::Query) {
+    if let Some(prev) = PREV_POST_PARSE_ANALYZE_HOOK {
+        prev(pstate, query);
+    }
+    redact_activity(pstate);
+}
+
+#[cfg(not(feature = "pg13"))]
+#[pg_guard]
+unsafe extern "C-unwind" fn redact_post_parse_analyze(
+pstate: *mut pg_sys::ParseState, query: *mut pg_sys::Query,
+jstate: *mut pg_sys::JumbleState) {
+    if let Some(prev) = PREV_POST_PARSE_ANALYZE_HOOK {
+        prev(pstate, query, jstate);
+    }
+    redact_activity(pstate);
+}
@@ -0,0 +1,235 @@
+use crate::common::{Header,PLAIN_INT,SEPARATOR};
+use crate::types::enigma::Enigma;
+use crate::types::enigma_ecc::Eecc;
+use crate::types::enigma_pgp::Epgp;
+use crate::types::enigma_rsa::Ersa;
+use crate::types::enigma_sym::Esym;
+use pgrx::datum::DatumWithOid;
+use pgrx::prelude::*;
+use pgrx::spi::{quote_identifier,quote_qualified_identifier};
+use pgrx::{AnyElement,PgRelation};
+
+/// Key id of the encrypted value, read from the Enigma header without
+/// decrypting it. NULL if the value is not encrypted.
+/// For `Esym` values it is the secret key id.
+#[pg_extern(immutable, parallel_safe)]
+fn enigma_key_id(value: AnyElement)
+-> Result<Option<i32>, Box<dyn std::error::Error + 'static>> {
+    Ok(value_header(value, "enigma_key_id")?.map(|h| h.key as i32))
+}
+
+/// First 64 bits (16 hex digits) of the fingerprint of the key the value
+/// was encrypted with, read from the Key Print header. NULL if the value
+/// is not encrypted or has no Key Print header.
+#[pg_extern(immutable, parallel_safe)]
+fn enigma_key_fingerprint(value: AnyElement)
+-> Result<Option<String>, Box<dyn std::error::Error + 'static>> {
+    Ok(value_header(value, "enigma_key_fingerprint")?
+        .and_then(|h| h.print).map(|p| format!("{:016X}", p)))
+}
+
+/// Enigma header of the value, `None` if the value is not encrypted
+fn value_header(value: AnyElement, function: &str)
+-> Result<Option<Header>, Box<dyn std::error::Error + 'static>> {
+    if ! is_enigma_type(value.oid()) && value.oid() != Esym::type_oid() {
+        return Err(format!("{}() argument must be an Enigma type", 
+            function).into());
+    }
+    let raw = unsafe { String::from_datum(value.datum(), false) }
+        .ok_or(format!("{}(): null value", function))?;
+    match raw.split_once(SEPARATOR) {
+        Some((header, _)) => Ok(Header::try_from(header).ok()
+            .filter(|h| h.tag != PLAIN_INT)),
+        None => Ok(None)
+    }
+}
+
+/// Re-encrypts the `column` values of table `rel` with key `new_key_id`
+/// in batches of `batch_size` rows. Values are decrypted with the session
+/// private keys. Returns one row per batch.
+/// All batches run in the caller's transaction.
+#[pg_extern(volatile)]
+fn enigma_rotate_column(rel: PgRelation, column: &str, new_key_id: i32,
+batch_size: default!(i32, 1000))
+-> Result<TableIterator<'static, (
+    name!(batch, i32), name!(reencrypted, i64), name!(remaining, i64)
+)>, Box<dyn std::error::Error + 'static>> {
+    if new_key_id < 0 {
+        return Err("Key id must be zero or greater".into());
+    }
+    if batch_size < 1 {
+        return Err("Batch size must be a positive integer".into());
+    }
+    check_column(&rel, column)?;
+    let table = quote_qualified_identifier(rel.namespace(), rel.name());
+    let column = quote_identifier(column);
+    let args = unsafe {
+        [ DatumWithOid::new(new_key_id, PgBuiltInOids::INT4OID.value()) ]
+    };
+    let mut remaining = Spi::get_one_with_args::<i64>(&format!(
+        "SELECT count(*) FROM {table} WHERE enigma_key_id({column}) <> $1"),
+        &args)?.unwrap_or(0);
+    let mut batches = Vec::new();
+    let mut batch = 0;
+    let mut after = String::from(FIRST_CTID);
+    while remaining > 0 {
+        let done = rotate_batch(&table, &column, new_key_id, None,
+            batch_size, &after)?;
+        after = match done.last_ctid {
+            Some(ctid) => ctid,
+            None => break
+        };
+        batch += 1;
+        remaining = (remaining - done.rotated).max(0);
+        info!("{table}.{column}: batch {batch}: {} values \
+            re-encrypted with key {new_key_id}, {remaining} remaining",
+            done.rotated);
+        batches.push((batch, done.rotated, remaining));
+    }
+    Ok(TableIterator::new(batches))
+}
+
+/// ctid before the first tuple
+pub const FIRST_CTID: &str = "(0,0)";
+
+/// Result of one `rotate_batch()`
+pub struct Batch {
+    /// values re-encrypted with the new key
+    pub rotated: i64,
+    /// checkpoint: last ctid in the batch, `None` if batch was empty
+    pub last_ctid: Option<String>
+}
+
+/// Re-encrypts up to `batch_size` values of `column` with key `new_key_id`,
+/// scanning `table` in ctid order after the `after` ctid. Each query reads
+/// only a window of blocks, so batches do not re-scan the whole table.
+/// Only values encrypted with `old_key_id` are re-encrypted when given,
+/// otherwise values encrypted with any other key.
+/// `table` and `column` must be already quoted identifiers.
+pub fn rotate_batch(table: &str, column: &str, new_key_id: i32, 
+old_key_id: Option<i32>, batch_size: i32, after: &str)
+-> Result<Batch, Box<dyn std::error::Error + 'static>> {
+    let (blocks, window) = block_window(table, batch_size)?;
+    let mut after = after.to_string();
+    let mut start = ctid_block(&after)?;
+    while start < blocks {
+        let end = (start + window).min(blocks);
+        let query = format!(
+            r#"WITH selected AS (
+                SELECT ctid FROM {table}
+                WHERE {} AND ctid > $3::tid AND enigma_key_id({column}) <> $1
+                AND ($4::int IS NULL OR enigma_key_id({column}) = $4)
+                ORDER BY ctid LIMIT $2
+            ), batch AS (
+                UPDATE {table} SET {column} = enigma_reencrypt({column}, $1)
+                WHERE ctid = ANY(ARRAY(SELECT ctid FROM selected))
+                AND enigma_key_id({column}) <> $1
+                AND ($4::int IS NULL OR enigma_key_id({column}) = $4)
+                RETURNING 1
+            ) SELECT (SELECT count(*) FROM batch),
+                (SELECT ctid FROM selected ORDER BY ctid DESC LIMIT 1)::text"#,
+            block_range(start, end));
+        let args = unsafe {
+            [
+                DatumWithOid::new(new_key_id, PgBuiltInOids::INT4OID.value()),
+                DatumWithOid::new(batch_size, PgBuiltInOids::INT4OID.value()),
+                DatumWithOid::new(after.as_str(),
+                    PgBuiltInOids::TEXTOID.value()),
+                DatumWithOid::new(old_key_id, PgBuiltInOids::INT4OID.value()),
+            ]
+        };
+        let (rotated, last_ctid) = 
+            Spi::get_two_with_args::<i64, String>(&query, &args)?;
+        if last_ctid.is_some() {
+            return Ok(Batch { rotated: rotated.unwrap_or(0), last_ctid });
+        }
+        // nothing to re-encrypt in this window
+        after = format!("({},0)", end);
+        start = end;
+    }
+    Ok(Batch { rotated: 0, last_ctid: None })
+}
+
+/// Column must exist and be an Enigma type
+pub fn check_column(rel: &PgRelation, column: &str)
+-> Result<(), Box<dyn std::error::Error + 'static>> {
+    let args = unsafe {
+        [
+            DatumWithOid::new(rel.oid(), PgBuiltInOids::OIDOID.value()),
+            DatumWithOid::new(column, PgBuiltInOids::TEXTOID.value()),
+        ]
+    };
+    let typid = Spi::get_one_with_args::<pg_sys::Oid>(
+        r#"SELECT atttypid FROM pg_attribute
+           WHERE attrelid = $1 AND attname = $2
+           AND attnum > 0 AND NOT attisdropped"#,
+        &args
+    )?.ok_or(format!("Column {} does not exist in {}",
+        column, rel.name()))?;
+    if ! is_enigma_type(typid) {
+        return Err(format!("Column {} is not an Enigma type", column).into());
+    }
+    Ok(())
+}
+
+/// Type oids of the public key Enigma types.
+/// `Esym` is not included: its type modifier is a secret key id.
+pub fn enigma_type_oids() -> Vec<pg_sys::Oid> {
+    vec![ Enigma::type_oid(), Epgp::type_oid(), 
+        Ersa::type_oid(), Eecc::type_oid() ]
+}
+
+/*********************
+ * PRIVATE FUNCTIONS *
+ * *******************/
+
+fn is_enigma_type(oid: pg_sys::Oid) -> bool {
+    enigma_type_oids().contains(&oid)
+}
+
+/// Maximum tuples in a heap block (`MaxHeapTuplesPerPage`)
+#[cfg(feature = "pg13")]
+const MAX_BLOCK_TUPLES: u32 = (pg_sys::BLCKSZ - 24) / 28;
+
+/// Blocks in `table`, and blocks per batch window estimated from the
+/// table statistics to hold about `batch_size` tuples
+fn block_window(table: &str, batch_size: i32)
+-> Result<(i64, i64), Box<dyn std::error::Error + 'static>> {
+    let args = unsafe {
+        [
+            DatumWithOid::new(table, PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(batch_size, PgBuiltInOids::INT4OID.value()),
+        ]
+    };
+    let (blocks, window) = Spi::get_two_with_args::<i64, i64>(
+        r#"SELECT pg_relation_size(oid)
+            / current_setting('block_size')::bigint,
+           CASE WHEN relpages > 0 AND reltuples > 0
+               THEN least(greatest(ceil($2 * relpages / reltuples), 1), $2)
+               ELSE 1 END::bigint
+           FROM pg_class WHERE oid = $1::regclass"#,
+        &args)?;
+    Ok((blocks.unwrap_or(0), window.unwrap_or(1)))
+}
+
+/// Block number of a `(block,offset)` ctid
+fn ctid_block(ctid: &str) -> Result<i64, Box<dyn std::error::Error + 'static>> {
+    ctid.trim_start_matches('(').split(',').next()
+        .and_then(|b| b.parse().ok())
+        .ok_or(format!("Invalid ctid: {}", ctid).into())
+}
+
+/// Condition on blocks `start` to `end` (excluded) run as a TID range scan
+#[cfg(not(feature = "pg13"))]
+fn block_range(start: i64, end: i64) -> String {
+    format!("ctid >= '({},0)'::tid AND ctid < '({},0)'::tid", start, end)
+}
+
+/// Condition on blocks `start` to `end` (excluded) run as a TID scan.
+/// Postgres 13 has no TID range scan.
+#[cfg(feature = "pg13")]
+fn block_range(start: i64, end: i64) -> String {
+    format!("ctid = ANY(ARRAY(SELECT format('(%s,%s)', b, o)::tid \
+        FROM generate_series({}::bigint, {}::bigint - 1) b, \
+        generate_series(1, {}) o))", start, end, MAX_BLOCK_TUPLES)
+}
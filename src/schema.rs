@@ -0,0 +1,61 @@
+use once_cell::sync::Lazy;
+use pgrx::datum::DatumWithOid;
+use pgrx::prelude::*;
+use pgrx::spi::quote_identifier;
+use std::sync::RwLock;
+
+/// pg_enigma extension oid, its schema oid and quoted schema name
+struct Schema {
+    extension: pg_sys::Oid,
+    oid: pg_sys::Oid,
+    name: String
+}
+
+static SCHEMA: Lazy<RwLock<Schema>> = Lazy::new(||
+    RwLock::new(Schema {
+        extension: pg_sys::InvalidOid,
+        oid: pg_sys::InvalidOid,
+        name: String::new()
+    }));
+
+/// pg_enigma table or function `object` qualified with the pg_enigma
+/// schema, so SPI queries do not depend on `search_path`.
+/// Not qualified if pg_enigma is not installed.
+pub fn qualified(object: &str) -> Result<String, pgrx::spi::Error> {
+    match extension_schema()? {
+        Some((_, name)) => Ok(format!("{}.{}", name, object)),
+        None => Ok(object.to_string())
+    }
+}
+
+/// Oid and quoted name of the pg_enigma schema, `None` if pg_enigma is not
+/// installed in the current database. Must be called in a transaction.
+pub fn extension_schema()
+-> Result<Option<(pg_sys::Oid, String)>, pgrx::spi::Error> {
+    let extension = unsafe {
+        pg_sys::get_extension_oid(c"pg_enigma".as_ptr(), true)
+    };
+    if extension == pg_sys::InvalidOid {
+        return Ok(None);
+    }
+    if let Ok(schema) = SCHEMA.read() {
+        if schema.extension == extension {
+            return Ok(Some((schema.oid, schema.name.clone())));
+        }
+    }
+    let args = unsafe {
+        [ DatumWithOid::new(extension, PgBuiltInOids::OIDOID.value()) ]
+    };
+    let (oid, name) = Spi::get_two_with_args::<pg_sys::Oid, String>(
+        "SELECT n.oid, n.nspname::text FROM pg_catalog.pg_extension e
+         JOIN pg_catalog.pg_namespace n ON n.oid = e.extnamespace
+         WHERE e.oid = $1", &args)?;
+    let (oid, name) = match (oid, name) {
+        (Some(oid), Some(name)) => (oid, quote_identifier(name)),
+        _ => return Ok(None)
+    };
+    if let Ok(mut schema) = SCHEMA.write() {
+        *schema = Schema { extension, oid, name: name.clone() };
+    }
+    Ok(Some((oid, name)))
+}
@@ -0,0 +1,76 @@
+use pgrx::warning;
+use std::alloc::{Layout,alloc_zeroed,dealloc,handle_alloc_error};
+use std::io::Error;
+use std::ptr::NonNull;
+use zeroize::Zeroize;
+
+/// Secret text (like a private key passphrase) kept in locked memory, so
+/// it is never swapped out, and zeroized when dropped.
+pub struct Secret(LockedBytes);
+
+impl Secret {
+    pub fn new(value: &str) -> Self {
+        Self(LockedBytes::new(value.as_bytes()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: the bytes were copied from a &str
+        unsafe { std::str::from_utf8_unchecked(self.0.as_bytes()) }
+    }
+}
+
+/// Bytes in their own locked memory pages, zeroized when dropped.
+/// Whole pages are allocated, so unlocking them does not unlock the memory
+/// of other secrets.
+pub struct LockedBytes {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// the allocation is owned and only read through shared references
+unsafe impl Send for LockedBytes {}
+unsafe impl Sync for LockedBytes {}
+
+impl LockedBytes {
+    /// Copies `value` to new locked pages
+    pub fn new(value: &[u8]) -> Self {
+        let page = page_size();
+        let size = value.len().max(1).div_ceil(page) * page;
+        let layout = Layout::from_size_align(size, page)
+            .expect("page size is a power of two");
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+        if unsafe { libc::mlock(ptr.as_ptr().cast(), size) } != 0 {
+            warning!("Could not lock secret in memory: {}",
+                Error::last_os_error());
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(value.as_ptr(), ptr.as_ptr(),
+                value.len());
+        }
+        Self { ptr, len: value.len(), layout }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for LockedBytes {
+    fn drop(&mut self) {
+        let size = self.layout.size();
+        unsafe {
+            std::slice::from_raw_parts_mut(self.ptr.as_ptr(), size).zeroize();
+            libc::munlock(self.ptr.as_ptr().cast(), size);
+            dealloc(self.ptr.as_ptr(), self.layout);
+        }
+    }
+}
+
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096
+    }
+}
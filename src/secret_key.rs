@@ -0,0 +1,162 @@
+use crate::common::{Decrypt,Encrypt,IsEncrypted,Plain};
+use crate::crypt::sym::{
+    SYM_KEY_LEN,kdf_derive,kdf_spec,sym_decrypt,sym_encrypt,sym_key_print
+};
+use crate::guc::{SECRET_KEY_CIPHER,SECRET_KEY_KDF};
+use crate::schema::qualified;
+use crate::secret::LockedBytes;
+use crate::types::enigma_sym::Esym;
+use pgrx::datum::DatumWithOid;
+use pgrx::{pg_extern,pg_sys,search_path,PgBuiltInOids,Spi};
+use zeroize::Zeroize;
+
+/// 256 bit symmetric key of `Esym` values, kept in locked memory and
+/// zeroized when dropped
+pub struct SecretKey {
+    key: LockedBytes,
+    /// first 64 bits of the key print, for the Key Print header
+    print: u64,
+    /// `raw` or the key derivation function
+    origin: String,
+    /// the KDF salt was created for this key in the current transaction
+    new_kdf: bool,
+}
+
+impl SecretKey {
+    /// Creates the secret key `id` from a 256 bit key (64 hex digits) or
+    /// from a passphrase. Passphrases are derived with the function and
+    /// salt in table _enigma_secret_keys, created from
+    /// `enigma.secret_key_kdf` the first time.
+    pub fn new(id: u32, key_or_passphrase: &str)
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        let trimmed = key_or_passphrase.trim();
+        if trimmed.len() == SYM_KEY_LEN * 2
+        && trimmed.chars().all(|c| c.is_ascii_hexdigit()) {
+            let mut raw = hex::decode(trimmed)?;
+            let key = Self::from_bytes(&raw, "raw", false);
+            raw.zeroize();
+            return key;
+        }
+        if key_or_passphrase.is_empty() {
+            return Err("Secret key passphrase can not be empty".into());
+        }
+        let (spec, new_kdf) = secret_key_kdf(id)?;
+        let mut derived = kdf_derive(&spec, key_or_passphrase)?;
+        let kdf = spec.split('$').next().unwrap_or_default();
+        let key = Self::from_bytes(&derived, kdf, new_kdf);
+        derived.zeroize();
+        key
+    }
+
+    fn from_bytes(bytes: &[u8], origin: &str, new_kdf: bool)
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        if bytes.len() != SYM_KEY_LEN {
+            return Err(format!("Secret key must be {} bytes",
+                SYM_KEY_LEN).into());
+        }
+        let key = LockedBytes::new(bytes);
+        Ok(SecretKey {
+            print: sym_key_print(key.as_bytes())?,
+            key,
+            origin: origin.to_string(),
+            new_kdf
+        })
+    }
+
+    pub fn secret_key_id(&self) -> String {
+        format!("{} {:016X}", self.origin, self.print)
+    }
+
+    /// True if the KDF salt was created with this key, and would be lost
+    /// if the transaction aborts
+    pub fn is_new_kdf(&self) -> bool {
+        self.new_kdf
+    }
+}
+
+impl Encrypt<Esym> for SecretKey {
+    fn encrypt(&self, id: u32, msg: Esym)
+    -> Result<Esym, Box<dyn std::error::Error + 'static>> {
+        if msg.is_encrypted() {
+             return Err("Nested encryption not supported".into());
+        }
+        let encrypted = sym_encrypt(self.key.as_bytes(),
+            SECRET_KEY_CIPHER.get().into(), msg.to_string())?;
+        Ok(Esym::sym(id, Some(self.print), encrypted))
+    }
+}
+
+impl Decrypt<Esym> for SecretKey {
+    fn decrypt(&self, msg: Esym)
+    -> Result<Esym, Box<dyn std::error::Error + 'static>> {
+        match msg {
+            Esym::SYM(id,print,msg) => {
+                if print.is_some_and(|p| p != self.print) {
+                    return Err(format!("Secret key {} is not the key the \
+                        value was encrypted with", id).into());
+                }
+                Ok(Esym::plain(sym_decrypt(self.key.as_bytes(), msg)?))
+            },
+            Esym::Plain(_) => Err("Already decrypted message".into())
+        }
+    }
+}
+
+/// KDF specification of the secret key `id` in table _enigma_secret_keys.
+/// A new one is created if there is none, then the second value is true.
+fn secret_key_kdf(id: u32)
+-> Result<(String, bool), Box<dyn std::error::Error + 'static>> {
+    if let Some(spec) = get_secret_key_kdf(id)? {
+        return Ok((spec, false));
+    }
+    let args = unsafe {
+        [ DatumWithOid::new(id as i32, PgBuiltInOids::INT4OID.value()) ]
+    };
+    let created = Spi::get_one_with_args::<String>(&format!(
+        "SELECT {}($1)", qualified("_enigma_new_secret_key_kdf")?), &args)?;
+    match created {
+        Some(spec) => Ok((spec, true)),
+        None => { // inserted by another session meanwhile
+            let spec = get_secret_key_kdf(id)?
+                .ok_or(format!("No KDF for secret key {}", id))?;
+            Ok((spec, false))
+        }
+    }
+}
+
+fn get_secret_key_kdf(id: u32) -> Result<Option<String>, pgrx::spi::Error> {
+    let args = unsafe {
+        [ DatumWithOid::new(id as i32, PgBuiltInOids::INT4OID.value()) ]
+    };
+    Spi::get_one_with_args(&format!(
+        "SELECT kdf FROM {} WHERE id = $1", qualified("_enigma_secret_keys")?),
+        &args)
+}
+
+/// Creates the KDF specification of the secret key `id`, with a random
+/// salt, from `enigma.secret_key_kdf`. NULL if the key already has one.
+/// Roles can not write _enigma_secret_keys, so they choose neither the
+/// KDF parameters nor the salt.
+#[pg_extern(volatile, security_definer, requires = [ "secret_keys" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
+fn _enigma_new_secret_key_kdf(id: i32)
+-> Result<Option<String>, Box<dyn std::error::Error + 'static>> {
+    if id < 1 {
+        return Err("Key id must be a positive integer".into());
+    }
+    let spec = kdf_spec(SECRET_KEY_KDF.get().into())?;
+    let args = unsafe {
+        [
+            DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()),
+            DatumWithOid::new(spec.as_str(), PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(pg_sys::GetOuterUserId(),
+                PgBuiltInOids::OIDOID.value()),
+        ]
+    };
+    Ok(Spi::get_one_with_args::<String>(&format!(
+        "INSERT INTO {}(id, kdf, created_by)
+         VALUES ($1, $2,
+             (SELECT rolname FROM pg_catalog.pg_roles WHERE oid = $3))
+         ON CONFLICT(id) DO NOTHING RETURNING kdf", 
+        qualified("_enigma_secret_keys")?), &args)?)
+}
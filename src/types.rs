@@ -1,5 +1,7 @@
 pub mod enigma;
+pub mod enigma_ecc;
 pub mod enigma_pgp;
 pub mod enigma_rsa;
+pub mod enigma_sym;
 mod legacy;
 
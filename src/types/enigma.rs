@@ -3,6 +3,7 @@ use crate::common::*;
 use crate::{PRIV_KEYS,PUB_KEYS};
 use crate::crypt::openssl::*;
 use crate::crypt::pgp::*;
+use crate::key_provider::key_provider;
 use enigma_macros::EnigmaType;
 use pgrx::callconv::{ArgAbi, BoxRet};
 use pgrx::datum::Datum;
@@ -15,18 +16,28 @@ use pgrx::pgrx_sql_entity_graph::metadata::{
     ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable
 };
 use std::fmt::{Display, Formatter};
+use super::enigma_ecc::{E_ECC_INT,E_ECC_TAG,Eecc};
 use super::enigma_pgp::{E_PGP_INT,E_PGP_TAG,Epgp};
-use super::enigma_rsa::{E_RSA_INT,E_RSA_TAG,Ersa};
+use super::enigma_rsa::{E_RSA_INT,E_RSA2_INT,Ersa,rsa_tag};
 use super::legacy::{ENIGMA_INT,Legacy};
 
+pub const E_KMS_TAG: &str = "PgE_KMS1"; // 0x5067455F4B4D5331
+pub const E_KMS_INT: u64  = 0x5067455F4B4D5331; // "PgE_KMS1"
+
 /// Value stores entcrypted information
 #[derive( Clone, Debug, EnigmaType)]
 #[enigma_impl( FullBoilerplate )]
 pub enum Enigma {
     /// PGP message
-    PGP(u32,String),
+    PGP(u32,Option<u64>,String),
+    /// PGP message encrypted to more keys (More Keys header)
+    PGPMulti(u32,Vec<u32>,Option<u64>,String),
     /// OpenSSL RSA encrypted message
-    RSA(u32,String), 
+    RSA(u32,Option<u64>,String), 
+    /// OpenSSL elliptic curve (ECIES) encrypted message
+    ECC(u32,Option<u64>,String),
+    /// Message encrypted with a data key wrapped by a key management service
+    KMS(u32,Option<u64>,String),
     /// Plain unencrypted message
     Plain(String)
 }
@@ -36,7 +47,7 @@ impl TryFrom<&str> for Enigma {
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         if let Some((header, payload)) = value.split_once(SEPARATOR) {
-            if let Ok(Header{tag,..}) = Header::try_from(header) {
+            if let Ok(Header{tag,key,print,..}) = Header::try_from(header) {
                 match tag {
                     PLAIN_INT => {
                         debug2!{"Plain unencrypted message"}
@@ -47,10 +58,18 @@ impl TryFrom<&str> for Enigma {
                         debug2!("PGP encrypted message");
                         return Ok(Self::from(Epgp::try_from(value)?));
                     },
-                    E_RSA_INT => {
+                    E_RSA_INT | E_RSA2_INT => {
                         debug2!("RSA encrypted message");
                         return Ok(Self::from(Ersa::try_from(value)?));
                     },
+                    E_ECC_INT => {
+                        debug2!("ECC encrypted message");
+                        return Ok(Self::from(Eecc::try_from(value)?));
+                    },
+                    E_KMS_INT => {
+                        debug2!("KMS encrypted message");
+                        return Ok(Self::KMS(key, print, payload.to_string()));
+                    },
                     ENIGMA_INT => {
                         return Ok(Self::from(Legacy::try_from(value)?));
                     },
@@ -69,7 +88,9 @@ impl TryFrom<&str> for Enigma {
 impl From<Epgp> for Enigma {
     fn from(value: Epgp) -> Self {
         match value {
-            Epgp::PGP(key,msg) => Self::PGP(key,msg),
+            Epgp::PGP(key,print,msg) => Self::PGP(key,print,msg),
+            Epgp::PGPMulti(key,more,print,msg) => 
+                Self::PGPMulti(key,more,print,msg),
             Epgp::Plain(msg) => Self::Plain(msg),
         }
     }
@@ -78,17 +99,26 @@ impl From<Epgp> for Enigma {
 impl From<Ersa> for Enigma {
     fn from(value: Ersa) -> Self {
         match value {
-            Ersa::RSA(key,msg) => Self::RSA(key,msg),
+            Ersa::RSA(key,print,msg) => Self::RSA(key,print,msg),
             Ersa::Plain(msg) => Self::Plain(msg),
         }
     }
 }
 
+impl From<Eecc> for Enigma {
+    fn from(value: Eecc) -> Self {
+        match value {
+            Eecc::ECC(key,print,msg) => Self::ECC(key,print,msg),
+            Eecc::Plain(msg) => Self::Plain(msg),
+        }
+    }
+}
+
 impl From<Legacy> for Enigma {
     fn from(value: Legacy) -> Self {
         match value {
-            Legacy::PGP(key,msg) => Self::PGP(key,msg),
-            Legacy::RSA(key,msg) => Self::RSA(key,msg),
+            Legacy::PGP(key,msg) => Self::PGP(key,None,msg),
+            Legacy::RSA(key,msg) => Self::RSA(key,None,msg),
             //Legacy::Plain(msg) => Self::Plain(msg),
         }
     }
@@ -97,15 +127,27 @@ impl From<Legacy> for Enigma {
 impl Display for Enigma {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Enigma::PGP(key,msg) => {
+            Enigma::PGP(key,print,msg) => {
                 // Use new Epgp header
-                write!(f, "{}{:08X}{}{}", 
-                E_PGP_TAG, key, SEPARATOR, msg)
+                write!(f, "{}{:08X}{}{}{}", 
+                E_PGP_TAG, key, key_print_header(print), SEPARATOR, msg)
+            },
+            Enigma::PGPMulti(key,more,print,msg) => {
+                write!(f, "{}{:08X}{}{}{}{}", E_PGP_TAG, key, 
+                more_keys_header(more), key_print_header(print), SEPARATOR, msg)
             },
-            Enigma::RSA(key,msg) => {
+            Enigma::RSA(key,print,msg) => {
                 // Use new Ersa header
-                write!(f, "{}{:08X}{}{}", 
-                E_RSA_TAG, key, SEPARATOR, msg)
+                write!(f, "{}{:08X}{}{}{}", 
+                rsa_tag(msg), key, key_print_header(print), SEPARATOR, msg)
+            },
+            Enigma::ECC(key,print,msg) => {
+                write!(f, "{}{:08X}{}{}{}", 
+                E_ECC_TAG, key, key_print_header(print), SEPARATOR, msg)
+            },
+            Enigma::KMS(key,print,msg) => {
+                write!(f, "{}{:08X}{}{}{}", 
+                E_KMS_TAG, key, key_print_header(print), SEPARATOR, msg)
             },
             Enigma::Plain(s) => {
                 write!(f, "{}", s)
@@ -115,30 +157,61 @@ impl Display for Enigma {
 }
 
 impl Enigma {
-    pub fn pgp(id: u32, value: String) -> Self {
-        Self::PGP(id, pgp_trim_envelope(value))
+    pub fn pgp(id: u32, print: Option<u64>, value: String) -> Self {
+        Self::PGP(id, print, pgp_trim_envelope(value))
+    }
+
+    pub fn rsa(id: u32, print: Option<u64>, value: String) -> Self {
+        Self::RSA(id, print, rsa_trim_envelope(value))
     }
 
-    pub fn rsa(id: u32, value: String) -> Self {
-        Self::RSA(id, rsa_trim_envelope(value))
+    pub fn ecc(id: u32, print: Option<u64>, value: String) -> Self {
+        Self::ECC(id, print, value)
+    }
+
+    pub fn kms(id: u32, print: Option<u64>, value: String) -> Self {
+        Self::KMS(id, print, value)
     }
 
     pub fn is_pgp(&self) -> bool {
-        matches!(*self, Self::PGP(_,_))
+        matches!(*self, Self::PGP(_,_,_) | Self::PGPMulti(_,_,_,_))
     }
 
     pub fn is_rsa(&self) -> bool {
-        matches!(*self, Self::RSA(_,_))
+        matches!(*self, Self::RSA(_,_,_))
+    }
+
+    pub fn is_ecc(&self) -> bool {
+        matches!(*self, Self::ECC(_,_,_))
+    }
+
+    pub fn is_kms(&self) -> bool {
+        matches!(*self, Self::KMS(_,_,_))
     }
 
     pub fn key_id(&self) -> Option<u32> {
         match self {
-            Self::RSA(k,_) => Some(*k),
-            Self::PGP(k,_) => Some(*k),
+            Self::RSA(k,_,_) => Some(*k),
+            Self::PGP(k,_,_) => Some(*k),
+            Self::PGPMulti(k,_,_,_) => Some(*k),
+            Self::ECC(k,_,_) => Some(*k),
+            Self::KMS(k,_,_) => Some(*k),
             Self::Plain(_) => None
         }
     }
 
+    /// All the key_ids the message is encrypted to
+    pub fn key_ids(&self) -> Vec<u32> {
+        match self {
+            Self::PGPMulti(k,more,_,_) => {
+                let mut keys = vec![*k];
+                keys.extend_from_slice(more);
+                keys
+            },
+            _ => self.key_id().into_iter().collect()
+        }
+    }
+
     /// Will look for the encryption key in it's key map and call
     /// the key's `encrypt()` function to encrypt the message.
     /// If no encrypting key is found, returns an error message.
@@ -163,23 +236,38 @@ impl Enigma {
         }
     }
 
+    /// Encrypts the message to all the keys in `ids`.
+    /// Only PGP keys can be used, see `Epgp::encrypt_multi()`.
+    pub fn encrypt_multi(self, ids: &[i32]) 
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        if self.is_encrypted() {
+            return Err("Nested encryption not supported".into());
+        }
+        let epgp = Epgp::plain(self.value()).encrypt_multi(ids)?;
+        Ok(Self::from(epgp))
+    }
+
     /// Will look for the decryption key in it's key map and call
     /// the key's `decrypt()` function to decrypt the message.
     /// If no decrypting key is found, returns the same encrypted message.
+    /// Messages encrypted to more keys can be decrypted with any of them.
+    /// KMS messages are decrypted by the key provider, if there is one.
     pub fn decrypt(self)
     -> Result<Enigma, Box<dyn std::error::Error + 'static>> {
-        let key_id = match self.key_id() {
-            Some(k) => k,
-            None => return Ok(self) // Not encrypted
-        };
-        debug2!("Decrypt: Message key_id: {key_id}");
-        match PRIV_KEYS.get(key_id)? {
-            Some(sec_key) => {
+        if self.is_kms() {
+            return match key_provider()? {
+                Some(provider) => provider.decrypt(self),
+                None => Ok(self) // No key provider
+            };
+        }
+        for key_id in self.key_ids() {
+            debug2!("Decrypt: Message key_id: {key_id}");
+            if let Some(sec_key) = PRIV_KEYS.get(key_id)? {
                 debug2!("Decrypt: got secret key");
-                sec_key.decrypt(self)
-            },
-            None => Ok(self)
+                return sec_key.decrypt(self);
+            }
         }
+        Ok(self) // Not encrypted or no private key
     }
 }
 
@@ -0,0 +1,161 @@
+use core::ffi::CStr;
+use crate::common::*;
+use crate::{PRIV_KEYS,PUB_KEYS};
+use crate::pub_key::PubKey;
+use crate::priv_key::PrivKey;
+use enigma_macros::EnigmaType;
+use pgrx::callconv::{ArgAbi, BoxRet};
+use pgrx::datum::Datum;
+use pgrx::{
+    debug1, debug2, debug5, error, info,
+    Array, FromDatum, Internal, IntoDatum, pg_extern, pg_sys, 
+    rust_regtypein, StringInfo
+};
+use pgrx::pgrx_sql_entity_graph::metadata::{
+    ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable
+};
+use std::fmt::{Display, Formatter};
+use super::enigma::Enigma;
+
+pub const E_ECC_TAG: &str = "PgE_ECC1"; // 0x5067455F45434331
+pub const E_ECC_INT: u64  = 0x5067455F45434331; // "PgE_ECC1"
+
+/// Value stores elliptic curve (ECIES) encrypted message
+#[derive( Clone, Debug, EnigmaType)]
+#[enigma_impl( FullBoilerplate )]
+pub enum Eecc {
+    /// ECIES message
+    ECC(u32,Option<u64>,String),
+    /// Plain unencrypted message
+    Plain(String)
+}
+
+impl TryFrom<&str> for Eecc {
+    type Error = Box<dyn std::error::Error + 'static>;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if let Some((header, payload)) = value.split_once(SEPARATOR) {
+            if let Ok(Header{tag,key,print,..}) = Header::try_from(header) {
+                match tag {
+                    PLAIN_INT => {
+                        debug2!{"Plain unencrypted message"}
+                        debug5!{"Payload: {payload}"}
+                        return Ok(Self::plain(payload.to_string()));
+                    },
+                    E_ECC_INT => {
+                        debug2!("ECC encrypted message");
+                        return Ok(Self::ECC(key, print, payload.to_string()));
+                    },
+                    _ => return Err(
+                        format!("Unknown Enigma header: {}", header).into())
+                }
+            } // non-parseable header is plain message
+        } // no header is plain message
+
+        debug2!("Not an Enigma message");
+        debug5!("Value: {value}");
+        Ok(Self::plain(value.to_string()))
+    }
+} 
+
+impl TryFrom<Enigma> for Eecc {
+    type Error = Box<dyn std::error::Error + 'static>;
+
+    fn try_from(value: Enigma) -> Result<Self, Self::Error> {
+        match value {
+            Enigma::ECC(key,print,msg) => Ok(Eecc::ECC(key,print,msg)),
+            _ => Err("Not an Enigma ECC message".into())
+        }
+    }
+}
+
+impl TryFrom<&Enigma> for Eecc {
+    type Error = Box<dyn std::error::Error + 'static>;
+
+    fn try_from(value: &Enigma) -> Result<Self, Self::Error> {
+        Self::try_from(value.clone())
+    }
+}
+
+impl Display for Eecc {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Eecc::ECC(key,print,msg) => {
+                write!(f, "{}{:08X}{}{}{}", 
+                E_ECC_TAG, key, key_print_header(print), SEPARATOR, msg)
+            },
+            Eecc::Plain(s) => {
+                write!(f, "{}", s)
+            }
+        }        
+    }
+}
+
+impl Eecc {
+    pub fn ecc(id: u32, print: Option<u64>, value: String) -> Self {
+        Self::ECC(id, print, value)
+    }
+
+    pub fn key_id(&self) -> Option<u32> {
+        match self {
+            Self::ECC(k,_,_) => Some(*k),
+            Self::Plain(_) => None
+        }
+    }
+
+    /// Will look for the encryption key in it's key map and call
+    /// the key's `encrypt()` function to encrypt the message.
+    /// If no encrypting key is found, returns an error message.
+    pub fn encrypt(self, id: i32) 
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        if id < 0 { 
+            return Err("Key id must be zero or greater".into());
+        }
+        let key_id: u32 = id as u32;
+        if let Some(msgid) = self.key_id() { // message is encrypted
+            if msgid == key_id {
+                info!("Already encrypted with key ID {msgid}"); 
+                return  Ok(self);
+            };
+            // TODO: try to decrypt
+            return Err("Nested encryption not supported".into());
+        }
+
+        if let Some(pub_key) = PUB_KEYS.get(key_id)? {
+            match *pub_key {
+                PubKey::ECC(..) => pub_key.encrypt(key_id, self),
+                _ => return Err(
+                    format!("Public key {} is not ECC", key_id).into())
+
+            }
+        } else {
+            Err(format!("No public key with key_id: {}", key_id).into())
+        }
+    }
+
+    /// Will look for the decryption key in it's key map and call
+    /// the key's `decrypt()` function to decrypt the message.
+    /// If no decrypting key is found, returns the same encrypted message.
+    pub fn decrypt(self)
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        let key_id = match self.key_id() {
+            Some(k) => k,
+            None => return Ok(self) // Not encrypted
+        };
+        debug2!("Decrypt: Message key_id: {key_id}");
+        match PRIV_KEYS.get(key_id)? {
+            Some(sec_key) => {
+                debug2!("Decrypt: got secret key");
+                match *sec_key {
+                    PrivKey::ECC(_) => sec_key.decrypt(self),
+                    #[cfg(feature = "pkcs11")]
+                    PrivKey::Pkcs11(ref k) if k.is_ecc() => 
+                        sec_key.decrypt(self),
+                    _ => return Err(
+                        format!("Private key {} is not ECC", key_id).into())
+                }
+            },
+            None => Ok(self)
+        }
+    }
+}
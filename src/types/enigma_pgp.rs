@@ -2,7 +2,7 @@ use core::ffi::CStr;
 use crate::common::*;
 use crate::{PRIV_KEYS,PUB_KEYS};
 use crate::crypt::pgp::*;
-use crate::pub_key::PubKey;
+use crate::pub_key::{PubKey,expired_before};
 use crate::priv_key::PrivKey;
 use enigma_macros::EnigmaType;
 use pgrx::callconv::{ArgAbi, BoxRet};
@@ -27,7 +27,9 @@ pub const E_PGP_INT: u64  = 0x5067455F50475031; // "PgE_PGP1"
 #[enigma_impl( FullBoilerplate )]
 pub enum Epgp {
     /// PGP message
-    PGP(u32,String),
+    PGP(u32,Option<u64>,String),
+    /// PGP message encrypted to more keys (More Keys header)
+    PGPMulti(u32,Vec<u32>,Option<u64>,String),
     /// Plain unencrypted message
     Plain(String)
 }
@@ -37,7 +39,7 @@ impl TryFrom<&str> for Epgp {
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         if let Some((header, payload)) = value.split_once(SEPARATOR) {
-            if let Ok(Header{tag,key}) = Header::try_from(header) {
+            if let Ok(Header{tag,key,more,print}) = Header::try_from(header) {
                 match tag {
                     PLAIN_INT => {
                         debug2!{"Plain unencrypted message"}
@@ -46,7 +48,8 @@ impl TryFrom<&str> for Epgp {
                     },
                     E_PGP_INT => {
                         debug2!("PGP encrypted message");
-                        return Ok(Self::pgp(key, payload.to_string()));
+                        return Ok(Self::pgp_multi(key, more, print,
+                            payload.to_string()));
                     },
                     ENIGMA_INT => {
                         return Self::try_from(Legacy::try_from(value)?);
@@ -68,7 +71,9 @@ impl TryFrom<Enigma> for Epgp {
 
     fn try_from(value: Enigma) -> Result<Self, Self::Error> {
         match value {
-            Enigma::PGP(key,msg) => Ok(Epgp::PGP(key,msg)),
+            Enigma::PGP(key,print,msg) => Ok(Epgp::PGP(key,print,msg)),
+            Enigma::PGPMulti(key,more,print,msg) => 
+                Ok(Epgp::PGPMulti(key,more,print,msg)),
             _ => Err("Not an Enigma PGP message".into())
         }
     }
@@ -87,7 +92,7 @@ impl TryFrom<Legacy> for Epgp {
 
     fn try_from(value: Legacy) -> Result<Self, Self::Error> {
         match value {
-            Legacy::PGP(key,msg) => Ok(Self::PGP(key,msg)),
+            Legacy::PGP(key,msg) => Ok(Self::PGP(key,None,msg)),
             _ => Err("Not a legacy Enigma PGP message".into())
         }
     }
@@ -96,9 +101,13 @@ impl TryFrom<Legacy> for Epgp {
 impl Display for Epgp {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Epgp::PGP(key,msg) => {
-                write!(f, "{}{:08X}{}{}", 
-                E_PGP_TAG, key, SEPARATOR, msg)
+            Epgp::PGP(key,print,msg) => {
+                write!(f, "{}{:08X}{}{}{}", 
+                E_PGP_TAG, key, key_print_header(print), SEPARATOR, msg)
+            },
+            Epgp::PGPMulti(key,more,print,msg) => {
+                write!(f, "{}{:08X}{}{}{}{}", E_PGP_TAG, key, 
+                more_keys_header(more), key_print_header(print), SEPARATOR, msg)
             },
             Epgp::Plain(s) => {
                 write!(f, "{}", s)
@@ -108,8 +117,18 @@ impl Display for Epgp {
 }
 
 impl Epgp {
-    pub fn pgp(id: u32, value: String) -> Self {
-        Self::PGP(id, pgp_trim_envelope(value))
+    pub fn pgp(id: u32, print: Option<u64>, value: String) -> Self {
+        Self::PGP(id, print, pgp_trim_envelope(value))
+    }
+
+    /// PGP message encrypted to key `id` and `more` keys.
+    /// Without more keys it is a regular PGP message.
+    pub fn pgp_multi(id: u32, more: Vec<u32>, print: Option<u64>, 
+    value: String) -> Self {
+        if more.is_empty() {
+            return Self::pgp(id, print, value);
+        }
+        Self::PGPMulti(id, more, print, pgp_trim_envelope(value))
     }
 
     /* pub fn is_pgp(&self) -> bool {
@@ -118,11 +137,25 @@ impl Epgp {
 
     pub fn key_id(&self) -> Option<u32> {
         match self {
-            Self::PGP(k,_) => Some(*k),
+            Self::PGP(k,_,_) => Some(*k),
+            Self::PGPMulti(k,_,_,_) => Some(*k),
             Self::Plain(_) => None
         }
     }
 
+    /// All the key_ids the message is encrypted to
+    pub fn key_ids(&self) -> Vec<u32> {
+        match self {
+            Self::PGP(k,_,_) => vec![*k],
+            Self::PGPMulti(k,more,_,_) => {
+                let mut keys = vec![*k];
+                keys.extend_from_slice(more);
+                keys
+            },
+            Self::Plain(_) => Vec::new()
+        }
+    }
+
     /// Will look for the encryption key in it's key map and call
     /// the key's `encrypt()` function to encrypt the message.
     /// If no encrypting key is found, returns an error message.
@@ -142,8 +175,8 @@ impl Epgp {
         }
 
         if let Some(pub_key) = PUB_KEYS.get(key_id)? {
-            match pub_key {
-                PubKey::PGP(_) => pub_key.encrypt(key_id, self),
+            match *pub_key {
+                PubKey::PGP(..) => pub_key.encrypt(key_id, self),
                 _ => return Err(
                     format!("Public key {} is not PGP", key_id).into())
 
@@ -153,27 +186,70 @@ impl Epgp {
         }
     }
 
+    /// Encrypts the message to all the keys in `ids`, so any of their 
+    /// private keys can decrypt it. First id goes in the Enigma header, 
+    /// the rest in the More Keys header. All keys must be PGP.
+    pub fn encrypt_multi(self, ids: &[i32]) 
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        if self.is_encrypted() {
+            return Err("Nested encryption not supported".into());
+        }
+        let mut key_ids: Vec<u32> = Vec::with_capacity(ids.len());
+        for id in ids {
+            if *id < 0 { 
+                return Err("Key id must be zero or greater".into());
+            }
+            if !key_ids.contains(&(*id as u32)) {
+                key_ids.push(*id as u32);
+            }
+        }
+        let mut keys = Vec::with_capacity(key_ids.len());
+        for key_id in &key_ids {
+            match PUB_KEYS.get(*key_id)? {
+                Some(key) => match *key {
+                    PubKey::PGP(..) => {
+                        key.check_usable();
+                        keys.push(key);
+                    },
+                    _ => return Err(
+                        format!("Public key {} is not PGP", key_id).into()),
+                },
+                None => return Err(
+                    format!("No public key with key_id: {}", key_id).into())
+            }
+        }
+        let pub_keys: Vec<_> = keys.iter()
+            .filter_map(|key| match key.as_ref() {
+                PubKey::PGP(k, _) => Some(k),
+                _ => None
+            }).collect();
+        // header key fingerprint
+        let print = keys.first().and_then(|key| key.key_print());
+        let (key_id, more) = key_ids.split_first()
+            .ok_or("At least one key id is required")?;
+        let encrypted = pgp_encrypt_to_keys(&pub_keys, expired_before(), 
+            self.to_string())?;
+        Ok(Self::pgp_multi(*key_id, more.to_vec(), print, encrypted))
+    }
+
     /// Will look for the decryption key in it's key map and call
     /// the key's `decrypt()` function to decrypt the message.
     /// If no decrypting key is found, returns the same encrypted message.
+    /// Messages encrypted to more keys can be decrypted with any of them.
     pub fn decrypt(self)
     -> Result<Self, Box<dyn std::error::Error + 'static>> {
-        let key_id = match self.key_id() {
-            Some(k) => k,
-            None => return Ok(self) // Not encrypted
-        };
-        debug2!("Decrypt: Message key_id: {key_id}");
-        match PRIV_KEYS.get(key_id)? {
-            Some(sec_key) => {
+        for key_id in self.key_ids() {
+            debug2!("Decrypt: Message key_id: {key_id}");
+            if let Some(sec_key) = PRIV_KEYS.get(key_id)? {
                 debug2!("Decrypt: got secret key");
-                match sec_key {
+                return match *sec_key {
                     PrivKey::PGP(_,_) => sec_key.decrypt(self),
-                    _ => return Err(
+                    _ => Err(
                         format!("Private key {} is not PGP", key_id).into())
-                }
-            },
-            None => Ok(self)
+                };
+            }
         }
+        Ok(self) // Not encrypted or no private key
     }
 }
 
@@ -21,13 +21,15 @@ use super::legacy::*;
 
 pub const E_RSA_TAG: &str = "PgE_RSA1"; // 0x5067455F52534131
 pub const E_RSA_INT: u64  = 0x5067455F52534131; // "PgE_RSA1"
+pub const E_RSA2_TAG: &str = "PgE_RSA2"; // 0x5067455F52534132
+pub const E_RSA2_INT: u64  = 0x5067455F52534132; // "PgE_RSA2"
 
 /// Value stores RSA-encrypted message
 #[derive( Clone, Debug, EnigmaType)]
 #[enigma_impl( FullBoilerplate )]
 pub enum Ersa {
     /// RSA message
-    RSA(u32,String),
+    RSA(u32,Option<u64>,String),
     /// Plain unencrypted message
     Plain(String)
 }
@@ -37,7 +39,7 @@ impl TryFrom<&str> for Ersa {
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         if let Some((header, payload)) = value.split_once(SEPARATOR) {
-            if let Ok(Header{tag,key}) = Header::try_from(header) {
+            if let Ok(Header{tag,key,print,..}) = Header::try_from(header) {
                 match tag {
                     PLAIN_INT => {
                         debug2!{"Plain unencrypted message"}
@@ -46,7 +48,14 @@ impl TryFrom<&str> for Ersa {
                     },
                     E_RSA_INT => {
                         debug2!("RSA encrypted message");
-                        return Ok(Self::rsa(key, payload.to_string()));
+                        return Ok(Self::rsa(key, print, payload.to_string()));
+                    },
+                    E_RSA2_INT => {
+                        debug2!("RSA encrypted message with scheme line");
+                        if ! rsa_is_versioned(payload) {
+                            return Err("Missing RSA scheme line".into());
+                        }
+                        return Ok(Self::rsa(key, print, payload.to_string()));
                     },
                     ENIGMA_INT => {
                         return Self::try_from(Legacy::try_from(value)?);
@@ -68,7 +77,7 @@ impl TryFrom<Enigma> for Ersa {
 
     fn try_from(value: Enigma) -> Result<Self, Self::Error> {
         match value {
-            Enigma::RSA(key,msg) => Ok(Ersa::RSA(key,msg)),
+            Enigma::RSA(key,print,msg) => Ok(Ersa::RSA(key,print,msg)),
             _ => Err("Not an Enigma RSA message".into())
         }
     }
@@ -87,7 +96,7 @@ impl TryFrom<Legacy> for Ersa {
 
     fn try_from(value: Legacy) -> Result<Self, Self::Error> {
         match value {
-            Legacy::RSA(key,msg) => Ok(Self::RSA(key,msg)),
+            Legacy::RSA(key,msg) => Ok(Self::RSA(key,None,msg)),
             _ => Err("Not a legacy Enigma RSA message".into())
         }
     }
@@ -96,9 +105,9 @@ impl TryFrom<Legacy> for Ersa {
 impl Display for Ersa {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Ersa::RSA(key,msg) => {
-                write!(f, "{}{:08X}{}{}", 
-                E_RSA_TAG, key, SEPARATOR, msg)
+            Ersa::RSA(key,print,msg) => {
+                write!(f, "{}{:08X}{}{}{}", 
+                rsa_tag(msg), key, key_print_header(print), SEPARATOR, msg)
             },
             Ersa::Plain(s) => {
                 write!(f, "{}", s)
@@ -107,18 +116,23 @@ impl Display for Ersa {
     }
 }
 
+/// Versioned payloads (with scheme line) use `PgE_RSA2` header
+pub fn rsa_tag(msg: &str) -> &'static str {
+    if rsa_is_versioned(msg) { E_RSA2_TAG } else { E_RSA_TAG }
+}
+
 impl Ersa {
-    pub fn rsa(id: u32, value: String) -> Self {
-        Self::RSA(id, rsa_trim_envelope(value))
+    pub fn rsa(id: u32, print: Option<u64>, value: String) -> Self {
+        Self::RSA(id, print, rsa_trim_envelope(value))
     }
 
     /* pub fn is_rsa(&self) -> bool {
-        matches!(*self, Self::RSA(_,_))
+        matches!(*self, Self::RSA(_,_,_))
     } */
 
     pub fn key_id(&self) -> Option<u32> {
         match self {
-            Self::RSA(k,_) => Some(*k),
+            Self::RSA(k,_,_) => Some(*k),
             Self::Plain(_) => None
         }
     }
@@ -142,8 +156,8 @@ impl Ersa {
         }
 
         if let Some(pub_key) = PUB_KEYS.get(key_id)? {
-            match pub_key {
-                PubKey::RSA(_) => pub_key.encrypt(key_id, self),
+            match *pub_key {
+                PubKey::RSA(..) => pub_key.encrypt(key_id, self),
                 _ => return Err(
                     format!("Public key {} is not RSA", key_id).into())
 
@@ -166,8 +180,11 @@ impl Ersa {
         match PRIV_KEYS.get(key_id)? {
             Some(sec_key) => {
                 debug2!("Decrypt: got secret key");
-                match sec_key {
+                match *sec_key {
                     PrivKey::RSA(_) => sec_key.decrypt(self),
+                    #[cfg(feature = "pkcs11")]
+                    PrivKey::Pkcs11(ref k) if k.is_rsa() => 
+                        sec_key.decrypt(self),
                     _ => return Err(
                         format!("Private key {} is not RSA", key_id).into())
                 }
@@ -0,0 +1,123 @@
+use core::ffi::CStr;
+use crate::common::*;
+use crate::SECRET_KEYS;
+use enigma_macros::EnigmaType;
+use pgrx::callconv::{ArgAbi, BoxRet};
+use pgrx::datum::Datum;
+use pgrx::{
+    debug1, debug2, debug5, error, info,
+    Array, FromDatum, Internal, IntoDatum, pg_extern, pg_sys, 
+    rust_regtypein, StringInfo
+};
+use pgrx::pgrx_sql_entity_graph::metadata::{
+    ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable
+};
+use std::fmt::{Display, Formatter};
+
+pub const E_SYM_TAG: &str = "PgE_SYM1"; // 0x5067455F53594D31
+pub const E_SYM_INT: u64  = 0x5067455F53594D31; // "PgE_SYM1"
+
+/// Value stores symmetric (secret key) encrypted message
+#[derive( Clone, Debug, EnigmaType)]
+#[enigma_impl( FullBoilerplate, NumericTypmod )]
+pub enum Esym {
+    /// AES-256-GCM or XChaCha20-Poly1305 message
+    SYM(u32,Option<u64>,String),
+    /// Plain unencrypted message
+    Plain(String)
+}
+
+impl TryFrom<&str> for Esym {
+    type Error = Box<dyn std::error::Error + 'static>;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if let Some((header, payload)) = value.split_once(SEPARATOR) {
+            if let Ok(Header{tag,key,print,..}) = Header::try_from(header) {
+                match tag {
+                    PLAIN_INT => {
+                        debug2!{"Plain unencrypted message"}
+                        debug5!{"Payload: {payload}"}
+                        return Ok(Self::plain(payload.to_string()));
+                    },
+                    E_SYM_INT => {
+                        debug2!("SYM encrypted message");
+                        return Ok(Self::SYM(key, print, payload.to_string()));
+                    },
+                    _ => return Err(
+                        format!("Unknown Enigma header: {}", header).into())
+                }
+            } // non-parseable header is plain message
+        } // no header is plain message
+
+        debug2!("Not an Enigma message");
+        debug5!("Value: {value}");
+        Ok(Self::plain(value.to_string()))
+    }
+} 
+
+impl Display for Esym {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Esym::SYM(key,print,msg) => {
+                write!(f, "{}{:08X}{}{}{}", 
+                E_SYM_TAG, key, key_print_header(print), SEPARATOR, msg)
+            },
+            Esym::Plain(s) => {
+                write!(f, "{}", s)
+            }
+        }        
+    }
+}
+
+impl Esym {
+    pub fn sym(id: u32, print: Option<u64>, value: String) -> Self {
+        Self::SYM(id, print, value)
+    }
+
+    pub fn key_id(&self) -> Option<u32> {
+        match self {
+            Self::SYM(k,_,_) => Some(*k),
+            Self::Plain(_) => None
+        }
+    }
+
+    /// Will look for the secret key in it's key map and call
+    /// the key's `encrypt()` function to encrypt the message.
+    /// If no secret key is set, returns an error message.
+    pub fn encrypt(self, id: i32) 
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        if id < 0 { 
+            return Err("Key id must be zero or greater".into());
+        }
+        let key_id: u32 = id as u32;
+        if let Some(msgid) = self.key_id() { // message is encrypted
+            if msgid == key_id {
+                info!("Already encrypted with key ID {msgid}"); 
+                return  Ok(self);
+            };
+            return Err("Nested encryption not supported".into());
+        }
+
+        match SECRET_KEYS.get(key_id)? {
+            Some(sec_key) => sec_key.encrypt(key_id, self),
+            None => Err(format!("No secret key with key_id: {}. \
+                Use set_secret_key()", key_id).into())
+        }
+    }
+
+    /// Will look for the secret key in it's key map and call
+    /// the key's `decrypt()` function to decrypt the message.
+    /// If no secret key is set, returns the same encrypted message.
+    pub fn decrypt(self)
+    -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        let key_id = match self.key_id() {
+            Some(k) => k,
+            None => return Ok(self) // Not encrypted
+        };
+        debug2!("Decrypt: Message key_id: {key_id}");
+        match SECRET_KEYS.get(key_id)? {
+            Some(sec_key) => sec_key.decrypt(self),
+            None => Ok(self)
+        }
+    }
+}
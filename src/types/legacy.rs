@@ -25,7 +25,7 @@ impl TryFrom<&str> for Legacy {
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         if let Some((header, payload)) = value.split_once(SEPARATOR) {
-            if let Ok(Header{tag,key}) = Header::try_from(header) {
+            if let Ok(Header{tag,key,..}) = Header::try_from(header) {
                 match tag {
                     /* PLAIN_INT => { // unnecessary
                         debug2!{"Plain unencrypted message"}
@@ -0,0 +1,304 @@
+use crate::priv_key::PrivKey;
+use crate::pub_key::key_id_from_name;
+use crate::schema::qualified;
+use crate::secret::LockedBytes;
+use once_cell::sync::Lazy;
+use pgrx::datum::DatumWithOid;
+use pgrx::prelude::*;
+use pgrx::shmem::PGRXSharedMemory;
+use pgrx::{pg_shmem_init,register_xact_callback,PgLwLock,PgXactCallbackEvent};
+use std::collections::BTreeMap;
+use std::sync::{Arc,RwLock};
+use std::sync::atomic::{AtomicBool,Ordering};
+
+/// Private keys the vault can hold
+const VAULT_SLOTS: usize = 16;
+/// Maximum unlocked key material length
+const VAULT_KEY_LEN: usize = 16384;
+
+/// Private key stored in shared memory as unlocked key material (see
+/// `PrivKey::unlocked()`): the passphrase is never stored.
+/// Free slots have key id 0.
+#[derive(Clone, Copy)]
+struct VaultSlot {
+    id: u32,
+    kind: u8,
+    key_len: usize,
+    key: [u8; VAULT_KEY_LEN],
+}
+
+impl VaultSlot {
+    const EMPTY: Self = VaultSlot {
+        id: 0,
+        kind: 0,
+        key_len: 0,
+        key: [0; VAULT_KEY_LEN],
+    };
+
+    /// Key material kind, and the key material copied to backend 
+    /// (locked) memory
+    fn read(&self) -> (u8, LockedBytes) {
+        (self.kind, LockedBytes::new(&self.key[..self.key_len]))
+    }
+
+    fn write(&mut self, id: u32, kind: u8, key: &[u8]) {
+        self.clear();
+        self.id = id;
+        self.kind = kind;
+        self.key_len = key.len();
+        self.key[..key.len()].copy_from_slice(key);
+    }
+
+    /// Zeroes the key material and frees the slot
+    fn clear(&mut self) {
+        *self = Self::EMPTY;
+    }
+}
+
+/// Shared memory private key vault
+#[derive(Clone, Copy)]
+pub struct Vault {
+    /// Changes every time a key or a grant changes, so backends drop
+    /// their cached vault keys
+    generation: u64,
+    slots: [VaultSlot; VAULT_SLOTS],
+}
+
+unsafe impl PGRXSharedMemory for Vault {}
+
+impl Default for Vault {
+    fn default() -> Self {
+        Vault { generation: 0, slots: [VaultSlot::EMPTY; VAULT_SLOTS] }
+    }
+}
+
+static VAULT: PgLwLock<Vault> = unsafe { PgLwLock::new(c"pg_enigma_vault") };
+
+/// Set when the vault shared memory was requested
+static VAULT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Vault keys parsed by this backend for `role` at vault `generation`.
+/// `None` entries are keys not in the vault or not granted to the role.
+struct VaultCache {
+    generation: u64,
+    role: pg_sys::Oid,
+    keys: BTreeMap<u32,Option<Arc<PrivKey>>>,
+}
+
+static VAULT_CACHE: Lazy<RwLock<VaultCache>> = Lazy::new(||
+    RwLock::new(VaultCache {
+        generation: 0,
+        role: pg_sys::InvalidOid,
+        keys: BTreeMap::new()
+    }));
+
+/// Requests the vault shared memory.
+/// Only possible when loaded from `shared_preload_libraries`.
+pub fn init() {
+    if ! unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+    pg_shmem_init!(VAULT);
+    VAULT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    VAULT_ENABLED.load(Ordering::Relaxed)
+}
+
+fn check_enabled() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    if is_enabled() {
+        Ok(())
+    } else {
+        Err("The private key vault requires pg_enigma in \
+            shared_preload_libraries".into())
+    }
+}
+
+/// Private key `id` from the vault, if the current role was granted it.
+/// Called by `PrivKeysMap::get()` when the session has no key `id`.
+pub fn get(id: u32)
+-> Result<Option<Arc<PrivKey>>, Box<dyn std::error::Error + 'static>> {
+    if ! is_enabled() {
+        return Ok(None);
+    }
+    let role = unsafe { pg_sys::GetUserId() };
+    let generation = VAULT.share().generation;
+    match VAULT_CACHE.write() {
+        Ok(mut cache) => {
+            if cache.generation != generation || cache.role != role {
+                cache.keys.clear(); // old keys are dropped
+                cache.generation = generation;
+                cache.role = role;
+            }
+            if let Some(key) = cache.keys.get(&id) {
+                return Ok(key.clone());
+            }
+        },
+        Err(e) => return Err(
+            format!("Vault: get: could not get write lock: {}", e).into()),
+    }
+
+    // no locks held while reading the grants with SPI
+    let slot = read_slot(id);
+    let key = match slot {
+        Some((kind, key)) if is_granted(id)? => {
+            debug1!("Vault: private key {} granted", id);
+            Some(Arc::new(PrivKey::from_unlocked(kind, key.as_bytes())?))
+        },
+        _ => None
+    };
+    match VAULT_CACHE.write() {
+        Ok(mut cache) => { cache.keys.insert(id, key.clone()); },
+        Err(e) => return Err(
+            format!("Vault: get: could not get write lock: {}", e).into()),
+    }
+    Ok(key)
+}
+
+/// Key material kind and key material of the vault slot with key `id`
+fn read_slot(id: u32) -> Option<(u8, LockedBytes)> {
+    let vault = VAULT.share();
+    vault.slots.iter().find(|s| s.id == id).map(|slot| slot.read())
+}
+
+/// Superusers, and roles with the privileges of a role granted key `id`.
+/// The grants are read by a SECURITY DEFINER function: roles can not
+/// read nor shadow (with a temporary table) the grants table.
+fn is_granted(id: u32) -> Result<bool, pgrx::spi::Error> {
+    if unsafe { pg_sys::superuser() } {
+        return Ok(true);
+    }
+    let args = unsafe {
+        [
+            DatumWithOid::new(id as i32, PgBuiltInOids::INT4OID.value()),
+            DatumWithOid::new(pg_sys::GetUserId(),
+                PgBuiltInOids::OIDOID.value()),
+        ]
+    };
+    Ok(Spi::get_one_with_args::<bool>(&format!("SELECT {}($1, $2)",
+        qualified("_enigma_vault_granted")?), &args)?.unwrap_or(false))
+}
+
+/// Stores the unlocked private key material in the vault, never the
+/// passphrase. Backends drop their cached vault keys right away: shared
+/// memory is not transactional.
+pub fn store(id: u32, key: &str, pass: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    let priv_key = PrivKey::new(key, pass)?;
+    let priv_id = priv_key.priv_key_id();
+    let (kind, unlocked) = priv_key.unlocked()?;
+    drop(priv_key);
+    if unlocked.len() > VAULT_KEY_LEN {
+        return Err(format!("Private key is too long for the vault \
+            ({} bytes, maximum {})", unlocked.len(), VAULT_KEY_LEN).into());
+    }
+    {
+        let mut vault = VAULT.exclusive();
+        // same key id slot or the first free slot
+        let i = vault.slots.iter().position(|s| s.id == id)
+            .or_else(|| vault.slots.iter().position(|s| s.id == 0))
+            .ok_or(format!("The private key vault is full ({} keys)",
+                VAULT_SLOTS))?;
+        vault.slots[i].write(id, kind, &unlocked);
+        vault.generation += 1;
+    }
+    Ok(format!("key {}: private key {} stored in the vault", id, priv_id))
+}
+
+/// Backends drop their cached vault keys once the grant is committed
+fn bump_generation_at_commit() {
+    register_xact_callback(PgXactCallbackEvent::Commit,
+        || VAULT.exclusive().generation += 1);
+}
+
+/// Stores the private key in the shared memory vault, where roles granted
+/// with `grant_vault_private_key()` can use it from any backend.
+/// The key is kept unlocked in the vault, without the passphrase.
+/// Takes effect at once, even if the transaction rolls back.
+#[pg_extern(volatile)]
+fn set_vault_private_key(id: i32, key: &str, pass: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    check_enabled()?;
+    if id < 1 {
+        return Err("Key id must be a positive integer".into());
+    }
+    store(id as u32, key, pass)
+}
+
+/// Same as `set_vault_private_key()` using the key name
+#[pg_extern(volatile, name = "set_vault_private_key",
+    requires = [ "shell_type" ])]
+fn set_vault_private_key_by_name(name: &str, key: &str, pass: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    set_vault_private_key(key_id_from_name(name)?, key, pass)
+}
+
+/// Removes the private key from the vault, zeroing its slot.
+/// Takes effect at once, even if the transaction rolls back.
+#[pg_extern(volatile)]
+fn forget_vault_private_key(id: i32)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    check_enabled()?;
+    if id < 1 {
+        return Err("Key id must be a positive integer".into());
+    }
+    let mut vault = VAULT.exclusive();
+    match vault.slots.iter_mut().find(|s| s.id == id as u32) {
+        Some(slot) => slot.clear(),
+        None => return Ok(format!("No private key {} in the vault", id))
+    }
+    vault.generation += 1;
+    Ok(format!("key {}: private key removed from the vault", id))
+}
+
+/// Same as `forget_vault_private_key()` using the key name
+#[pg_extern(volatile, name = "forget_vault_private_key",
+    requires = [ "shell_type" ])]
+fn forget_vault_private_key_by_name(name: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    forget_vault_private_key(key_id_from_name(name)?)
+}
+
+/// Lets `role` and its members use the vault private key `id`
+#[pg_extern(volatile, security_definer, requires = [ "vault" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
+fn grant_vault_private_key(id: i32, role: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    check_enabled()?;
+    let args = unsafe {
+        [
+            DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()),
+            DatumWithOid::new(role, PgBuiltInOids::TEXTOID.value()),
+            DatumWithOid::new(pg_sys::GetOuterUserId(),
+                PgBuiltInOids::OIDOID.value()),
+        ]
+    };
+    Spi::run_with_args(&format!(
+        "INSERT INTO {}(id, role, granted_by)
+         VALUES ($1, $2::regrole::name,
+             (SELECT rolname FROM pg_catalog.pg_roles WHERE oid = $3))
+         ON CONFLICT DO NOTHING", qualified("_enigma_vault_grants")?),
+        &args)?;
+    bump_generation_at_commit();
+    Ok(format!("key {}: vault private key granted to {}", id, role))
+}
+
+/// Revokes `grant_vault_private_key()`
+#[pg_extern(volatile, security_definer, requires = [ "vault" ])]
+#[search_path(pg_catalog, @extschema@, pg_temp)]
+fn revoke_vault_private_key(id: i32, role: &str)
+-> Result<String, Box<dyn std::error::Error + 'static>> {
+    check_enabled()?;
+    let args = unsafe {
+        [
+            DatumWithOid::new(id, PgBuiltInOids::INT4OID.value()),
+            DatumWithOid::new(role, PgBuiltInOids::TEXTOID.value()),
+        ]
+    };
+    Spi::run_with_args(&format!(
+        "DELETE FROM {} WHERE id = $1 AND role = $2",
+        qualified("_enigma_vault_grants")?), &args)?;
+    bump_generation_at_commit();
+    Ok(format!("key {}: vault private key revoked from {}", id, role))
+}
@@ -0,0 +1,33 @@
+#!/bin/sh
+# Creates a SoftHSMv2 token with an RSA test key generated in the token
+# (sensitive, never extractable) for the pkcs11 feature tests:
+#
+#   eval $(test/softhsm-setup.sh)
+#   cargo pgrx test --features pkcs11
+#
+# Set PKCS11_MODULE if libsofthsm2.so is not in /usr/lib/softhsm
+set -e
+TEST_DIR=$(cd "$(dirname "$0")" && pwd)
+HSM_DIR=$TEST_DIR/../target/softhsm
+MODULE=${PKCS11_MODULE:-/usr/lib/softhsm/libsofthsm2.so}
+
+rm -rf "$HSM_DIR"
+mkdir -p "$HSM_DIR/tokens"
+export SOFTHSM2_CONF=$HSM_DIR/softhsm2.conf
+echo "directories.tokendir = $HSM_DIR/tokens" > "$SOFTHSM2_CONF"
+
+SLOT=$(softhsm2-util --init-token --free --label enigma-test \
+	--pin 1234 --so-pin 4321 | sed -n 's/.*reassigned to slot \([0-9]*\).*/\1/p')
+# imported keys were extractable once: the key is generated in the token
+pkcs11-tool --module "$MODULE" --slot "$SLOT" --login --pin 1234 \
+	--keypairgen --key-type rsa:2048 --label alice --id 01 >&2
+pkcs11-tool --module "$MODULE" --slot "$SLOT" --read-object --type pubkey \
+	--id 01 -o "$HSM_DIR/alice_public.der" >&2
+openssl pkey -pubin -inform DER -in "$HSM_DIR/alice_public.der" \
+	-out "$HSM_DIR/alice_public.pem"
+rm -f "$HSM_DIR/alice_public.der"
+
+echo "export SOFTHSM2_CONF=$SOFTHSM2_CONF"
+echo "export ENIGMA_PKCS11_MODULE=$MODULE"
+echo "export ENIGMA_PKCS11_SLOT=$SLOT"
+echo "export ENIGMA_PKCS11_PUBLIC_KEY=$HSM_DIR/alice_public.pem"